@@ -1,7 +1,6 @@
 use std::io::{self, Error, ErrorKind, Read, Write};
 use std::net::{SocketAddr, TcpStream};
-use std::convert::TryInto;
-use std::ffi::CString;
+use std::os::unix::ffi::OsStringExt;
 use std::vec;
 
 fn msg(msg: &str) {
@@ -52,39 +51,43 @@ fn write_all(fd: &mut TcpStream, buf: &[u8]) -> io::Result<()> {
     Ok(())
 }
 
-const K_MAX_MSG: usize = 4096;
+const K_MAX_MSG: usize = 512 << 20;
 
-fn send_req(fd: &mut TcpStream, cmd: &Vec<String>) -> io::Result<()> {
-    let mut len = 4u32;
+fn send_req(fd: &mut TcpStream, cmd: &Vec<Vec<u8>>) -> io::Result<()> {
+    let mut len = 4usize;
     for s in cmd {
-        len += 4 + s.len() as u32;
+        len += 4 + s.len();
     }
-    if len > K_MAX_MSG as u32 {
+    if len > K_MAX_MSG {
         return Err(Error::new(ErrorKind::InvalidInput, "message too large"));
     }
 
-    let mut wbuf = vec![0; 4 + K_MAX_MSG];
-    wbuf[0..4].copy_from_slice(&len.to_le_bytes());
+    let mut wbuf = vec![0; 4 + len];
+    wbuf[0..4].copy_from_slice(&(len as u32).to_le_bytes());
     let n = cmd.len() as u32;
     wbuf[4..8].copy_from_slice(&n.to_le_bytes());
     let mut cur = 8usize;
     for s in cmd {
         let p = s.len() as u32;
         wbuf[cur..cur + 4].copy_from_slice(&p.to_le_bytes());
-        wbuf[cur + 4..cur + 4 + s.len()].copy_from_slice(s.as_bytes());
+        wbuf[cur + 4..cur + 4 + s.len()].copy_from_slice(s);
         cur += 4 + s.len();
     }
 
-    write_all(fd, &wbuf[0..(4 + len) as usize])?;
+    write_all(fd, &wbuf)?;
     Ok(())
 }
 
+// The reply types, a tag byte followed by little-endian fields. Must match
+// `Serialization` in src/main.rs.
+#[allow(non_camel_case_types)]
 enum Serialization {
-    SER_NIL = 0,
-    SER_ERR = 1,
-    SER_STR = 2,
-    SER_INT = 3,
-    SER_ARR = 4,
+    SER_NIL = 0, // nothing
+    SER_ERR = 1, // code u32 | len u32 | message
+    SER_STR = 2, // len u32 | bytes
+    SER_INT = 3, // a signed 64-bit integer
+    SER_ARR = 4, // len u32, then len nested replies
+    SER_DBL = 5, // an IEEE-754 double
 }
 
 fn on_response(data: &[u8]) -> io::Result<usize> {
@@ -94,11 +97,11 @@ fn on_response(data: &[u8]) -> io::Result<usize> {
     }
 
     match data[0] {
-        0 => {
+        t if t == Serialization::SER_NIL as u8 => {
             println!("(nil)");
             Ok(1)
         }
-        1 => {
+        t if t == Serialization::SER_ERR as u8 => {
             if data.len() < 1 + 8 {
                 msg("bad response");
                 return Err(Error::new(ErrorKind::InvalidData, "bad response"));
@@ -113,11 +116,10 @@ fn on_response(data: &[u8]) -> io::Result<usize> {
                 msg("bad response");
                 return Err(Error::new(ErrorKind::InvalidData, "bad response"));
             }
-            let msg = CString::new(&data[9..9 + len]).expect("CString::new failed");
-            println!("(err) {} {}", code, msg.to_str().unwrap());
+            println!("(err) {} {}", code, data[9..9 + len].escape_ascii());
             Ok(1 + 8 + len)
         }
-        2 => {
+        t if t == Serialization::SER_STR as u8 => {
             if data.len() < 1 + 4 {
                 msg("bad response");
                 return Err(Error::new(ErrorKind::InvalidData, "bad response"));
@@ -129,11 +131,11 @@ fn on_response(data: &[u8]) -> io::Result<usize> {
                 msg("bad response");
                 return Err(Error::new(ErrorKind::InvalidData, "bad response"));
             }
-            let msg = CString::new(&data[5..5 + len]).expect("CString::new failed");
-            println!("(str) {}", msg.to_str().unwrap());
+            // values are arbitrary bytes, print anything unprintable escaped
+            println!("(str) {}", data[5..5 + len].escape_ascii());
             Ok(1 + 4 + len)
         }
-        3 => {
+        t if t == Serialization::SER_INT as u8 => {
             if data.len() < 1 + 8 {
                 msg("bad response");
                 return Err(Error::new(ErrorKind::InvalidData, "bad response"));
@@ -144,7 +146,7 @@ fn on_response(data: &[u8]) -> io::Result<usize> {
             println!("(int) {}", val);
             Ok(1 + 8)
         }
-        4 => {
+        t if t == Serialization::SER_ARR as u8 => {
             if data.len() < 1 + 4 {
                 msg("bad response");
                 return Err(Error::new(ErrorKind::InvalidData, "bad response"));
@@ -164,6 +166,17 @@ fn on_response(data: &[u8]) -> io::Result<usize> {
             println!("(arr) end");
             Ok(arr_bytes)
         }
+        t if t == Serialization::SER_DBL as u8 => {
+            if data.len() < 1 + 8 {
+                msg("bad response");
+                return Err(Error::new(ErrorKind::InvalidData, "bad response"));
+            }
+            let mut val_bytes = [0; 8];
+            val_bytes.copy_from_slice(&data[1..9]);
+            let val = f64::from_le_bytes(val_bytes);
+            println!("(dbl) {}", val);
+            Ok(1 + 8)
+        }
         _ => {
             msg("bad response");
             Err(Error::new(ErrorKind::InvalidData, "bad response"))
@@ -173,20 +186,18 @@ fn on_response(data: &[u8]) -> io::Result<usize> {
 }
 
 fn read_res(fd: &mut TcpStream) -> io::Result<()> {
-    let mut rbuf = vec![0; 4 + K_MAX_MSG + 1];
-    read_full(fd, &mut rbuf[0..4])?;
-    let len = u32::from_le_bytes(rbuf[0..4].try_into().unwrap()) as usize;
-    if len > K_MAX_MSG {
-        msg("too long");
-        return Err(Error::new(ErrorKind::InvalidData, "too long"));
-    }
-    read_full(fd, &mut rbuf[4..(4 + len)])?;
-    on_response(&rbuf[4..(4 + len)])?;
+    let mut len_bytes = [0; 4];
+    read_full(fd, &mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    let mut rbuf = vec![0; len];
+    read_full(fd, &mut rbuf)?;
+    on_response(&rbuf)?;
     Ok(())
 }
 
 fn main() -> io::Result<()> {
-    let mut args: Vec<String> = std::env::args().collect();
+    // arguments are sent as raw bytes, they need not be valid UTF-8
+    let mut args: Vec<Vec<u8>> = std::env::args_os().map(|a| a.into_vec()).collect();
     args.remove(0); // Remove the first argument (program name)
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
@@ -0,0 +1,371 @@
+use crate::glob;
+use crate::sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+// command categories, granted with `+@<name>` and taken with `-@<name>`
+pub const READ: u32 = 1 << 0;
+pub const WRITE: u32 = 1 << 1;
+pub const ADMIN: u32 = 1 << 2;
+pub const PUBSUB: u32 = 1 << 3;
+const ALL: u32 = READ | WRITE | ADMIN | PUBSUB;
+
+const CATEGORIES: [(&str, u32); 5] = [
+    ("all", ALL),
+    ("read", READ),
+    ("write", WRITE),
+    ("admin", ADMIN),
+    ("pubsub", PUBSUB),
+];
+
+/// A user clients authenticate as, and what it may do.
+#[derive(Clone)]
+pub struct User {
+    pub enabled: bool,
+    // any password is accepted
+    nopass: bool,
+    // the SHA-256 digests of the passwords, in hex
+    passwords: Vec<String>,
+    categories: u32,
+    // glob patterns of the keys it may access
+    keys: Vec<Vec<u8>>,
+}
+
+impl User {
+    /// A user that is off and may do nothing, until rules say otherwise.
+    fn new() -> User {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            categories: 0,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Applies one rule in the syntax of redis' ACL SETUSER: `on`, `off`,
+    /// `>password`, `<password`, `#digest`, `nopass`, `resetpass`,
+    /// `+@category`, `-@category`, `allcommands`, `nocommands`,
+    /// `~pattern`, `allkeys`, `resetkeys` and `reset`.
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let category = |name: &str| {
+            CATEGORIES
+                .iter()
+                .find(|(category, _)| *category == name)
+                .map(|(_, bits)| *bits)
+                .ok_or_else(|| format!("unknown category '{name}'"))
+        };
+        match rule {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allcommands" => self.categories = ALL,
+            "nocommands" => self.categories = 0,
+            "allkeys" => self.keys = vec![b"*".to_vec()],
+            "resetkeys" => self.keys.clear(),
+            "reset" => *self = User::new(),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.add_password(sha256::hex_digest(password.as_bytes()));
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    let digest = sha256::hex_digest(password.as_bytes());
+                    self.passwords.retain(|p| *p != digest);
+                } else if let Some(digest) = rule.strip_prefix('#') {
+                    let hex = digest
+                        .bytes()
+                        .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+                    if digest.len() != 64 || !hex {
+                        return Err(format!("bad password digest '{digest}'"));
+                    }
+                    self.add_password(digest.to_string());
+                } else if let Some(name) = rule.strip_prefix("+@") {
+                    self.categories |= category(name)?;
+                } else if let Some(name) = rule.strip_prefix("-@") {
+                    self.categories &= !category(name)?;
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    self.keys.push(pattern.as_bytes().to_vec());
+                } else {
+                    return Err(format!("unknown rule '{rule}'"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, digest: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&digest) {
+            self.passwords.push(digest);
+        }
+    }
+
+    fn check_password(&self, password: &[u8]) -> bool {
+        if self.nopass {
+            return true;
+        }
+        let digest = sha256::hex_digest(password);
+        // compared in full whatever the first difference, not to tell
+        // through timing how close a guess was
+        let same = |p: &String| {
+            p.bytes()
+                .zip(digest.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+        };
+        self.passwords.iter().any(same)
+    }
+
+    /// Returns `true` if commands of `category` may be run, those of no
+    /// category always may.
+    pub fn can_run(&self, category: u32) -> bool {
+        category & !self.categories == 0
+    }
+
+    pub fn can_access(&self, key: &[u8]) -> bool {
+        self.keys.iter().any(|pattern| glob::matches(pattern, key))
+    }
+
+    /// Whether any password is accepted, so clients start out
+    /// authenticated as the `default` user.
+    pub fn is_open(&self) -> bool {
+        self.enabled && self.nopass
+    }
+
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn passwords(&self) -> &[String] {
+        &self.passwords
+    }
+
+    /// The categories as rules, e.g. `+@read +@pubsub`.
+    pub fn commands(&self) -> String {
+        if self.categories == ALL {
+            return "+@all".to_string();
+        }
+        if self.categories == 0 {
+            return "-@all".to_string();
+        }
+        let granted: Vec<String> = CATEGORIES[1..]
+            .iter()
+            .filter(|(_, bits)| self.categories & bits != 0)
+            .map(|(name, _)| format!("+@{name}"))
+            .collect();
+        granted.join(" ")
+    }
+
+    pub fn keys(&self) -> &[Vec<u8>] {
+        &self.keys
+    }
+
+    /// Returns the rules that rebuild this user, as ACL LIST shows them
+    /// and the users file stores them.
+    fn rules(&self) -> String {
+        let mut rules: Vec<String> = self.flags().iter().map(|f| f.to_string()).collect();
+        rules.extend(self.passwords.iter().map(|p| format!("#{p}")));
+        rules.extend(
+            self.keys
+                .iter()
+                .map(|k| format!("~{}", String::from_utf8_lossy(k))),
+        );
+        rules.push(self.commands());
+        rules.join(" ")
+    }
+}
+
+/// The users of the server. There is always a `default` user, the one
+/// clients start out as; by default it accepts any password and may do
+/// anything, so a server without users configured is open as before.
+pub struct Acl {
+    users: BTreeMap<String, User>,
+}
+
+impl Acl {
+    pub fn new() -> Acl {
+        let mut default = User::new();
+        for rule in ["on", "nopass", "allkeys", "allcommands"] {
+            default.apply(rule).unwrap();
+        }
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), default);
+        Acl { users }
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Creates or modifies the user `name` with `rules`, applied in order.
+    /// Either they all apply or the user is left as it was.
+    pub fn set_user(&mut self, name: &str, rules: &[&str]) -> Result<(), String> {
+        let mut user = self.users.get(name).cloned().unwrap_or_else(User::new);
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Removes the user `name`, returns `false` if there was none. The
+    /// `default` user cannot be removed.
+    pub fn del_user(&mut self, name: &str) -> bool {
+        name != "default" && self.users.remove(name).is_some()
+    }
+
+    /// Checks a password of the user `name`, which must be enabled.
+    pub fn authenticate(&self, name: &str, password: &[u8]) -> bool {
+        self.users
+            .get(name)
+            .is_some_and(|user| user.enabled && user.check_password(password))
+    }
+
+    /// Returns every user as a `user <name> <rules>` line, sorted by name.
+    pub fn list(&self) -> Vec<String> {
+        self.users
+            .iter()
+            .map(|(name, user)| format!("user {name} {}", user.rules()))
+            .collect()
+    }
+
+    /// Reads a users file: `user <name> <rules>` lines, blank lines and
+    /// lines starting with `#` skipped. A `default` user not in the file
+    /// keeps its defaults.
+    pub fn load(path: &str) -> Result<Acl, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let mut acl = Acl::new();
+        for (i, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {}
+                [first, ..] if first.starts_with('#') => {}
+                ["user", name, rules @ ..] => {
+                    // the rules of the file are the whole user
+                    let mut rules = rules.to_vec();
+                    rules.insert(0, "reset");
+                    acl.set_user(name, &rules)
+                        .map_err(|e| format!("{path}:{}: {e}", i + 1))?;
+                }
+                _ => return Err(format!("{path}:{}: expected 'user <name> <rules>'", i + 1)),
+            }
+        }
+        Ok(acl)
+    }
+
+    /// Writes the users to `path` in the format `load` reads.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, self.list().join("\n") + "\n")?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_user_is_open() {
+        let acl = Acl::new();
+        let default = acl.user("default").unwrap();
+        assert!(default.is_open());
+        assert!(default.can_run(ALL));
+        assert!(default.can_access(b"any key"));
+        assert!(acl.authenticate("default", b"whatever"));
+        assert!(!acl.authenticate("nobody", b"whatever"));
+    }
+
+    #[test]
+    fn rules() {
+        let mut acl = Acl::new();
+        acl.set_user("reader", &["on", ">secret", "~cache:*", "+@read"])
+            .unwrap();
+        let reader = acl.user("reader").unwrap();
+        assert!(reader.can_run(READ));
+        assert!(!reader.can_run(WRITE));
+        assert!(reader.can_run(0));
+        assert!(reader.can_access(b"cache:1"));
+        assert!(!reader.can_access(b"other"));
+        assert_eq!(reader.commands(), "+@read");
+
+        acl.set_user("reader", &["+@all", "-@admin"]).unwrap();
+        let reader = acl.user("reader").unwrap();
+        assert!(reader.can_run(READ | WRITE | PUBSUB));
+        assert!(!reader.can_run(ADMIN));
+        assert_eq!(reader.commands(), "+@read +@write +@pubsub");
+
+        // a bad rule leaves the user as it was
+        assert!(acl.set_user("reader", &["-@all", "+@nothing"]).is_err());
+        assert!(acl.user("reader").unwrap().can_run(WRITE));
+        assert!(acl.set_user("reader", &["#abc"]).is_err());
+        assert!(acl.set_user("reader", &["bogus"]).is_err());
+
+        acl.set_user("reader", &["reset"]).unwrap();
+        let reader = acl.user("reader").unwrap();
+        assert!(!reader.enabled);
+        assert!(!reader.can_run(READ));
+        assert!(!reader.can_access(b"cache:1"));
+    }
+
+    #[test]
+    fn passwords() {
+        let mut acl = Acl::new();
+        acl.set_user("u", &["on", ">one", ">two"]).unwrap();
+        assert!(acl.authenticate("u", b"one"));
+        assert!(acl.authenticate("u", b"two"));
+        assert!(!acl.authenticate("u", b"three"));
+
+        acl.set_user("u", &["<one"]).unwrap();
+        assert!(!acl.authenticate("u", b"one"));
+        let digest = sha256::hex_digest(b"three");
+        acl.set_user("u", &[&format!("#{digest}")]).unwrap();
+        assert!(acl.authenticate("u", b"three"));
+
+        // a user that is off cannot authenticate, whatever the password
+        acl.set_user("u", &["off"]).unwrap();
+        assert!(!acl.authenticate("u", b"two"));
+        acl.set_user("u", &["on", "nopass"]).unwrap();
+        assert!(acl.authenticate("u", b"anything"));
+        acl.set_user("u", &["resetpass"]).unwrap();
+        assert!(!acl.authenticate("u", b"anything"));
+
+        assert!(!acl.del_user("default"));
+        assert!(acl.del_user("u"));
+        assert!(!acl.del_user("u"));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("acl-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut acl = Acl::new();
+        acl.set_user("default", &["off"]).unwrap();
+        acl.set_user("reader", &["on", ">secret", "~cache:*", "+@read"])
+            .unwrap();
+        acl.save(path).unwrap();
+
+        let loaded = Acl::load(path).unwrap();
+        assert_eq!(loaded.list(), acl.list());
+        assert!(!loaded.authenticate("default", b""));
+        assert!(loaded.authenticate("reader", b"secret"));
+
+        std::fs::write(path, "# users\n\nuser reader on +@bogus\n").unwrap();
+        let e = Acl::load(path).err().unwrap();
+        assert!(e.ends_with(":3: unknown category 'bogus'"), "{e}");
+        std::fs::write(path, "reader on\n").unwrap();
+        assert!(Acl::load(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+use crate::log::log;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// When the append-only file is flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every write, the safest and the slowest.
+    Always,
+    /// Once per second from a background thread, at most a second is lost.
+    EverySec,
+    /// Whenever the OS decides.
+    No,
+}
+
+impl FsyncPolicy {
+    /// Parses redis' `appendfsync` setting: `always`, `everysec` or `no`.
+    pub fn parse(s: &str) -> Option<FsyncPolicy> {
+        match s {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+}
+
+/// The append-only file. Every command that modified the keyspace is
+/// appended as a request in the same `len | body` framing the clients use,
+/// so replaying the file means parsing and running those requests again.
+pub struct Aof {
+    file: File,
+    size: u64,
+    policy: FsyncPolicy,
+    // set on every write, cleared by the fsync thread
+    dirty: Arc<AtomicBool>,
+}
+
+impl Aof {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &str, policy: FsyncPolicy) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        let dirty = Arc::new(AtomicBool::new(false));
+        // started whatever the policy since CONFIG SET may change it, it
+        // only syncs what `EverySec` marked dirty
+        let fsync_file = file.try_clone()?;
+        let fsync_dirty = dirty.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if fsync_dirty.swap(false, Ordering::AcqRel) {
+                if let Err(e) = fsync_file.sync_data() {
+                    log!(Warning, "aof fsync failed: {e}");
+                }
+            }
+        });
+        Ok(Aof {
+            file,
+            size,
+            policy,
+            dirty,
+        })
+    }
+
+    /// Appends one framed request.
+    pub fn append(&mut self, req: &[u8]) -> io::Result<()> {
+        self.file.write_all(req)?;
+        self.size += req.len() as u64;
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.dirty.store(true, Ordering::Release),
+            FsyncPolicy::No => {}
+        }
+        Ok(())
+    }
+
+    pub fn set_policy(&mut self, policy: FsyncPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the size of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Returns whether a record body is the request `name` without arguments,
+/// as `propagate_commit` frames MULTI and EXEC.
+fn is_bare(body: &[u8], name: &[u8]) -> bool {
+    let mut req = Vec::new();
+    req.extend(1u32.to_le_bytes());
+    req.extend((name.len() as u32).to_le_bytes());
+    req.extend(name);
+    body == req
+}
+
+/// Replays the append-only file at `path` from byte `offset` on, handing
+/// the body of every record to `apply`. A missing file is an empty log.
+///
+/// A record cut short at the end of the file (a crash in the middle of a
+/// write) is trimmed off when `trim_truncated` is set and is an error
+/// otherwise, and so is a transaction whose EXEC never made it: its
+/// records are held back until the EXEC, so none of them is replayed.
+/// Returns the number of records replayed.
+pub fn load<F>(path: &str, offset: u64, trim_truncated: bool, mut apply: F) -> io::Result<usize>
+where
+    F: FnMut(&[u8]) -> io::Result<()>,
+{
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut pos = offset as usize;
+    if pos > data.len() {
+        // the log was replaced after the snapshot was taken
+        log!(
+            Warning,
+            "{path} is shorter than the snapshot offset {offset}, replaying all of it"
+        );
+        pos = 0;
+    }
+    let mut nrec = 0;
+    // the offset of the MULTI of an open transaction, and its records
+    let mut tx: Option<(usize, Vec<(usize, usize)>)> = None;
+    let mut apply_at = |pos: usize, len: usize| {
+        apply(&data[pos + 4..pos + 4 + len]).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("bad aof record at offset {pos}: {e}"),
+            )
+        })
+    };
+    let mut cut = None;
+    while pos < data.len() {
+        let len = if data.len() - pos >= 4 {
+            u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize
+        } else {
+            usize::MAX
+        };
+        if len == usize::MAX || data.len() - pos - 4 < len {
+            cut = Some(("truncated aof record", pos));
+            break;
+        }
+        let body = &data[pos + 4..pos + 4 + len];
+        match tx.as_mut() {
+            None if is_bare(body, b"multi") => tx = Some((pos, vec![(pos, len)])),
+            None => {
+                apply_at(pos, len)?;
+                nrec += 1;
+            }
+            Some((_, records)) => {
+                records.push((pos, len));
+                if is_bare(body, b"exec") {
+                    for &(pos, len) in records.iter() {
+                        apply_at(pos, len)?;
+                    }
+                    nrec += records.len();
+                    tx = None;
+                }
+            }
+        }
+        pos += 4 + len;
+    }
+    if let Some((start, _)) = tx {
+        cut = Some(("aof transaction without exec", start));
+    }
+    if let Some((what, pos)) = cut {
+        if !trim_truncated {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{what} at offset {pos}"),
+            ));
+        }
+        log!(Warning, "trimming {what} at offset {pos}");
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(pos as u64)?;
+    }
+    Ok(nrec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Frames `cmd` the way the server logs it.
+    fn record(cmd: &[&str]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend((cmd.len() as u32).to_le_bytes());
+        for arg in cmd {
+            body.extend((arg.len() as u32).to_le_bytes());
+            body.extend(arg.as_bytes());
+        }
+        let mut req = (body.len() as u32).to_le_bytes().to_vec();
+        req.extend(body);
+        req
+    }
+
+    /// Replays a log holding `data`, returning the bodies replayed or the
+    /// error, and the size of the log afterwards.
+    fn replay(name: &str, data: &[u8], trim: bool) -> (io::Result<Vec<Vec<u8>>>, u64) {
+        let path = std::env::temp_dir().join(format!("aof-test-{}-{name}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, data).unwrap();
+        let mut bodies = Vec::new();
+        let result = load(path, 0, trim, |body| {
+            bodies.push(body.to_vec());
+            Ok(())
+        });
+        let size = fs::metadata(path).unwrap().len();
+        fs::remove_file(path).unwrap();
+        let result = result.map(|n| {
+            assert_eq!(n, bodies.len());
+            bodies
+        });
+        (result, size)
+    }
+
+    #[test]
+    fn replays_transactions_whole() {
+        let mut data = record(&["set", "a", "1"]);
+        data.extend(record(&["multi"]));
+        data.extend(record(&["set", "b", "2"]));
+        data.extend(record(&["exec"]));
+        data.extend(record(&["del", "a"]));
+        let (bodies, size) = replay("whole", &data, false);
+        let bodies = bodies.unwrap();
+        assert_eq!(bodies.len(), 5);
+        assert_eq!(bodies[3], &record(&["exec"])[4..]);
+        assert_eq!(size, data.len() as u64);
+    }
+
+    #[test]
+    fn log_cut_after_multi() {
+        let mut data = record(&["set", "a", "1"]);
+        let kept = data.len() as u64;
+        data.extend(record(&["multi"]));
+        data.extend(record(&["set", "b", "2"]));
+
+        let (bodies, size) = replay("multi-trim", &data, true);
+        assert_eq!(bodies.unwrap(), [record(&["set", "a", "1"])[4..].to_vec()]);
+        // what is appended after the restart is not part of the transaction
+        assert_eq!(size, kept);
+
+        let (result, size) = replay("multi-strict", &data, false);
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("aof transaction without exec at offset {kept}")
+        );
+        assert_eq!(size, data.len() as u64);
+    }
+
+    #[test]
+    fn log_cut_inside_a_record() {
+        let mut data = record(&["set", "a", "1"]);
+        let kept = data.len() as u64;
+        data.extend(&record(&["set", "b", "2"])[..10]);
+        let (bodies, size) = replay("record-trim", &data, true);
+        assert_eq!(bodies.unwrap().len(), 1);
+        assert_eq!(size, kept);
+
+        // cut inside a transaction, the whole transaction goes
+        let mut data = record(&["set", "a", "1"]);
+        data.extend(record(&["multi"]));
+        data.extend(record(&["set", "b", "2"]));
+        data.extend(&record(&["exec"])[..3]);
+        let (bodies, size) = replay("tx-record-trim", &data, true);
+        assert_eq!(bodies.unwrap().len(), 1);
+        assert_eq!(size, kept);
+    }
+
+    #[test]
+    fn missing_log_is_empty() {
+        let path = std::env::temp_dir().join("aof-test-missing-file");
+        assert_eq!(
+            load(path.to_str().unwrap(), 0, false, |_| Ok(())).unwrap(),
+            0
+        );
+    }
+}
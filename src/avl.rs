@@ -0,0 +1,352 @@
+use std::cmp::{max, Ordering};
+
+type Tree<K> = Option<Box<AvlNode<K>>>;
+
+/// A node in the AVL tree. Every node keeps the height and the number of
+/// nodes in its subtree, the latter is what makes rank queries O(log n).
+struct AvlNode<K> {
+    key: K,
+    height: u32,
+    cnt: usize,
+    left: Tree<K>,
+    right: Tree<K>,
+}
+
+impl<K> AvlNode<K> {
+    fn new(key: K) -> Box<Self> {
+        Box::new(AvlNode {
+            key,
+            height: 1,
+            cnt: 1,
+            left: None,
+            right: None,
+        })
+    }
+
+    /// Recomputes `height` and `cnt` from the children.
+    fn update(&mut self) {
+        self.height = 1 + max(height(&self.left), height(&self.right));
+        self.cnt = 1 + cnt(&self.left) + cnt(&self.right);
+    }
+}
+
+fn height<K>(tree: &Tree<K>) -> u32 {
+    tree.as_ref().map_or(0, |n| n.height)
+}
+
+fn cnt<K>(tree: &Tree<K>) -> usize {
+    tree.as_ref().map_or(0, |n| n.cnt)
+}
+
+fn rotate_left<K>(mut node: Box<AvlNode<K>>) -> Box<AvlNode<K>> {
+    let mut new_root = node.right.take().expect("rotate_left without right child");
+    node.right = new_root.left.take();
+    node.update();
+    new_root.left = Some(node);
+    new_root.update();
+    new_root
+}
+
+fn rotate_right<K>(mut node: Box<AvlNode<K>>) -> Box<AvlNode<K>> {
+    let mut new_root = node.left.take().expect("rotate_right without left child");
+    node.left = new_root.right.take();
+    node.update();
+    new_root.right = Some(node);
+    new_root.update();
+    new_root
+}
+
+/// Restores the AVL invariant at `node`, assuming both subtrees are balanced
+/// and differ in height by at most 2.
+fn balance<K>(mut node: Box<AvlNode<K>>) -> Box<AvlNode<K>> {
+    node.update();
+    let (l, r) = (height(&node.left), height(&node.right));
+    if l > r + 1 {
+        let left = node.left.take().unwrap();
+        node.left = Some(if height(&left.right) > height(&left.left) {
+            rotate_left(left)
+        } else {
+            left
+        });
+        return rotate_right(node);
+    }
+    if r > l + 1 {
+        let right = node.right.take().unwrap();
+        node.right = Some(if height(&right.left) > height(&right.right) {
+            rotate_right(right)
+        } else {
+            right
+        });
+        return rotate_left(node);
+    }
+    node
+}
+
+fn insert_at<K: Ord>(tree: Tree<K>, key: K) -> (Box<AvlNode<K>>, bool) {
+    let mut node = match tree {
+        None => return (AvlNode::new(key), true),
+        Some(node) => node,
+    };
+    let inserted = match key.cmp(&node.key) {
+        Ordering::Less => {
+            let (left, inserted) = insert_at(node.left.take(), key);
+            node.left = Some(left);
+            inserted
+        }
+        Ordering::Greater => {
+            let (right, inserted) = insert_at(node.right.take(), key);
+            node.right = Some(right);
+            inserted
+        }
+        Ordering::Equal => return (node, false),
+    };
+    (balance(node), inserted)
+}
+
+/// Detaches the smallest node of the subtree, returning the rest of the
+/// subtree and the detached node.
+fn remove_min<K>(mut node: Box<AvlNode<K>>) -> (Tree<K>, Box<AvlNode<K>>) {
+    match node.left.take() {
+        None => (node.right.take(), node),
+        Some(left) => {
+            let (rest, min) = remove_min(left);
+            node.left = rest;
+            (Some(balance(node)), min)
+        }
+    }
+}
+
+fn remove_at<K: Ord>(tree: Tree<K>, key: &K) -> (Tree<K>, Option<K>) {
+    let mut node = match tree {
+        None => return (None, None),
+        Some(node) => node,
+    };
+    match key.cmp(&node.key) {
+        Ordering::Less => {
+            let (left, removed) = remove_at(node.left.take(), key);
+            node.left = left;
+            (Some(balance(node)), removed)
+        }
+        Ordering::Greater => {
+            let (right, removed) = remove_at(node.right.take(), key);
+            node.right = right;
+            (Some(balance(node)), removed)
+        }
+        Ordering::Equal => {
+            let AvlNode {
+                key, left, right, ..
+            } = *node;
+            match (left, right) {
+                (None, rest) | (rest, None) => (rest, Some(key)),
+                (left, Some(right)) => {
+                    let (rest, mut min) = remove_min(right);
+                    min.left = left;
+                    min.right = rest;
+                    (Some(balance(min)), Some(key))
+                }
+            }
+        }
+    }
+}
+
+/// An AVL tree augmented with subtree sizes, usable as an ordered set with
+/// O(log n) rank and offset queries.
+pub struct AvlTree<K> {
+    root: Tree<K>,
+}
+
+impl<K: Ord> AvlTree<K> {
+    pub fn new() -> Self {
+        AvlTree { root: None }
+    }
+
+    pub fn len(&self) -> usize {
+        cnt(&self.root)
+    }
+
+    /// Inserts `key`, returns `false` if an equal key was already present.
+    pub fn insert(&mut self, key: K) -> bool {
+        let (root, inserted) = insert_at(self.root.take(), key);
+        self.root = Some(root);
+        inserted
+    }
+
+    /// Removes the key equal to `key` and hands it back.
+    pub fn remove(&mut self, key: &K) -> Option<K> {
+        let (root, removed) = remove_at(self.root.take(), key);
+        self.root = root;
+        removed
+    }
+
+    /// Returns the number of leading keys for which `pred` holds. `pred` must
+    /// be true for a prefix of the ordered keys and false for the rest.
+    pub fn partition_point<P: FnMut(&K) -> bool>(&self, mut pred: P) -> usize {
+        let mut cur = &self.root;
+        let mut rank = 0;
+        while let Some(node) = cur {
+            if pred(&node.key) {
+                rank += cnt(&node.left) + 1;
+                cur = &node.right;
+            } else {
+                cur = &node.left;
+            }
+        }
+        rank
+    }
+
+    /// Returns the zero-based rank of `key` if it is in the tree.
+    pub fn rank(&self, key: &K) -> Option<usize> {
+        let mut cur = &self.root;
+        let mut rank = 0;
+        while let Some(node) = cur {
+            match key.cmp(&node.key) {
+                Ordering::Less => cur = &node.left,
+                Ordering::Greater => {
+                    rank += cnt(&node.left) + 1;
+                    cur = &node.right;
+                }
+                Ordering::Equal => return Some(rank + cnt(&node.left)),
+            }
+        }
+        None
+    }
+
+    /// Iterates over the keys in order, starting at rank `n`.
+    pub fn iter_from(&self, mut n: usize) -> Iter<'_, K> {
+        let mut stack = Vec::new();
+        let mut cur = self.root.as_deref();
+        while let Some(node) = cur {
+            let l = cnt(&node.left);
+            match n.cmp(&l) {
+                Ordering::Less => {
+                    stack.push(node);
+                    cur = node.left.as_deref();
+                }
+                Ordering::Equal => {
+                    stack.push(node);
+                    break;
+                }
+                Ordering::Greater => {
+                    n -= l + 1;
+                    cur = node.right.as_deref();
+                }
+            }
+        }
+        Iter { stack }
+    }
+}
+
+/// In-order iterator over an `AvlTree`. The stack holds the nodes that are
+/// still to be visited together with their right subtrees.
+pub struct Iter<'a, K> {
+    stack: Vec<&'a AvlNode<K>>,
+}
+
+impl<'a, K> Iterator for Iter<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        let node = self.stack.pop()?;
+        let mut cur = node.right.as_deref();
+        while let Some(n) = cur {
+            self.stack.push(n);
+            cur = n.left.as_deref();
+        }
+        Some(&node.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Checks the heights, the subtree sizes and the balance of every node,
+    /// returning the height of `tree`.
+    fn check<K: Ord>(tree: &Tree<K>) -> u32 {
+        let Some(node) = tree else {
+            return 0;
+        };
+        let (l, r) = (check(&node.left), check(&node.right));
+        assert!(l.abs_diff(r) <= 1, "unbalanced node");
+        assert_eq!(node.height, 1 + max(l, r));
+        assert_eq!(node.cnt, 1 + cnt(&node.left) + cnt(&node.right));
+        if let Some(left) = &node.left {
+            assert!(left.key < node.key);
+        }
+        if let Some(right) = &node.right {
+            assert!(right.key > node.key);
+        }
+        node.height
+    }
+
+    // a fixed pseudo-random sequence, so failures reproduce
+    fn lcg(seed: &mut u64) -> u64 {
+        *seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        *seed >> 33
+    }
+
+    #[test]
+    fn insert_and_remove_keep_balance() {
+        let mut tree = AvlTree::new();
+        let mut model = BTreeSet::new();
+        let mut seed = 1;
+        for i in 0..4000 {
+            let key = lcg(&mut seed) % 500;
+            if i % 3 == 2 {
+                assert_eq!(tree.remove(&key), model.take(&key));
+            } else {
+                assert_eq!(tree.insert(key), model.insert(key));
+            }
+            assert_eq!(tree.len(), model.len());
+        }
+        check(&tree.root);
+        let keys: Vec<u64> = tree.iter_from(0).copied().collect();
+        assert_eq!(keys, model.iter().copied().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sorted_inserts_stay_logarithmic() {
+        let mut tree = AvlTree::new();
+        for key in 0..1024 {
+            tree.insert(key);
+        }
+        assert_eq!(check(&tree.root), 11);
+        for key in 0..1000 {
+            assert_eq!(tree.remove(&key), Some(key));
+        }
+        check(&tree.root);
+        assert_eq!(tree.len(), 24);
+    }
+
+    #[test]
+    fn rank_and_iter_from() {
+        let mut tree = AvlTree::new();
+        for key in (0..100).rev().map(|k| k * 2) {
+            tree.insert(key);
+        }
+        for rank in 0..100 {
+            assert_eq!(tree.rank(&(rank as i32 * 2)), Some(rank));
+            assert_eq!(tree.rank(&(rank as i32 * 2 + 1)), None);
+            assert_eq!(tree.iter_from(rank).next(), Some(&(rank as i32 * 2)));
+            assert_eq!(tree.iter_from(rank).count(), 100 - rank);
+        }
+        assert_eq!(tree.iter_from(100).next(), None);
+        assert_eq!(tree.iter_from(1000).next(), None);
+    }
+
+    #[test]
+    fn partition_point() {
+        let mut tree = AvlTree::new();
+        for key in [5, 1, 9, 3, 7] {
+            tree.insert(key);
+        }
+        assert_eq!(tree.partition_point(|k| *k < 0), 0);
+        assert_eq!(tree.partition_point(|k| *k < 4), 2);
+        assert_eq!(tree.partition_point(|k| *k <= 7), 4);
+        assert_eq!(tree.partition_point(|_| true), 5);
+        assert_eq!(AvlTree::<i32>::new().partition_point(|_| true), 0);
+    }
+}
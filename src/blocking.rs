@@ -0,0 +1,92 @@
+use lazy_static::lazy_static;
+use mio::Token;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+lazy_static! {
+    // keys that got pushed to since the event loop last served the clients
+    // blocked on them
+    static ref READY: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+/// Marks `key` as possibly having elements for blocked clients.
+pub fn signal_ready(key: &[u8]) {
+    READY.lock().unwrap().push(key.to_vec());
+}
+
+/// Takes the keys signaled since the last call.
+pub fn take_ready() -> Vec<Vec<u8>> {
+    std::mem::take(&mut *READY.lock().unwrap())
+}
+
+/// The connections blocked on keys, in the order they blocked, and their
+/// timeouts.
+pub struct Waiters {
+    by_key: HashMap<Vec<u8>, VecDeque<Token>>,
+    by_token: HashMap<Token, (Vec<Vec<u8>>, Option<Instant>)>,
+    deadlines: BTreeSet<(Instant, Token)>,
+}
+
+impl Waiters {
+    pub fn new() -> Waiters {
+        Waiters {
+            by_key: HashMap::new(),
+            by_token: HashMap::new(),
+            deadlines: BTreeSet::new(),
+        }
+    }
+
+    /// Queues `token` behind the clients already waiting on `keys`. Does
+    /// nothing if it is queued already.
+    pub fn add(&mut self, token: Token, keys: &[Vec<u8>], deadline: Option<Instant>) {
+        if self.by_token.contains_key(&token) {
+            return;
+        }
+        for key in keys {
+            self.by_key.entry(key.clone()).or_default().push_back(token);
+        }
+        if let Some(deadline) = deadline {
+            self.deadlines.insert((deadline, token));
+        }
+        self.by_token.insert(token, (keys.to_vec(), deadline));
+    }
+
+    pub fn remove(&mut self, token: Token) {
+        let Some((keys, deadline)) = self.by_token.remove(&token) else {
+            return;
+        };
+        for key in keys {
+            if let Some(queue) = self.by_key.get_mut(&key) {
+                queue.retain(|t| *t != token);
+                if queue.is_empty() {
+                    self.by_key.remove(&key);
+                }
+            }
+        }
+        if let Some(deadline) = deadline {
+            self.deadlines.remove(&(deadline, token));
+        }
+    }
+
+    /// Returns the clients waiting on `key`, longest waiting first.
+    pub fn waiting_on(&self, key: &[u8]) -> Vec<Token> {
+        self.by_key
+            .get(key)
+            .map_or_else(Vec::new, |queue| queue.iter().copied().collect())
+    }
+
+    /// Returns the earliest timeout of all blocked clients.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+
+    /// Returns the clients whose timeout is at or before `now`.
+    pub fn timed_out(&self, now: Instant) -> Vec<Token> {
+        self.deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .map(|(_, token)| *token)
+            .collect()
+    }
+}
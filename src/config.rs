@@ -0,0 +1,427 @@
+use crate::aof::FsyncPolicy;
+use crate::keyspace::EvictionPolicy;
+use crate::log::{self, Level};
+use crate::notify;
+use crate::G_AOF;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+
+/// The names of the settings, in the config file, on the command line and
+/// in CONFIG GET / SET.
+pub const NAMES: [&str; 24] = [
+    "bind",
+    "port",
+    "unixsocket",
+    "unixsocketperm",
+    "max-msg",
+    "max-query-buf",
+    "max-args",
+    "output-hard-limit",
+    "output-soft-limit",
+    "output-soft-seconds",
+    "poll-timeout",
+    "appendfilename",
+    "appendfsync",
+    "aof-load-truncated",
+    "dbfilename",
+    "notify-keyspace-events",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "repl-backlog-size",
+    "masteruser",
+    "masterauth",
+    "loglevel",
+    "aclfile",
+];
+
+// the settings only read at startup, which CONFIG SET refuses
+pub const IMMUTABLE: [&str; 6] = [
+    "bind",
+    "port",
+    "unixsocket",
+    "unixsocketperm",
+    "appendfilename",
+    "aclfile",
+];
+
+/// The settings of the server.
+#[derive(Clone)]
+pub struct Config {
+    // the config file they were loaded from
+    pub file: Option<String>,
+    pub bind: Vec<IpAddr>,
+    // 0 for no TCP listener
+    pub port: u16,
+    // the path of a Unix domain socket to listen on, empty for none
+    pub unixsocket: String,
+    // the permission bits of the socket file, 0 to leave them to the umask
+    pub unixsocketperm: u32,
+    // the largest request, and the largest string in a RESP request
+    pub max_msg: usize,
+    // the most unparsed input a client may have buffered
+    pub max_query_buf: usize,
+    pub max_args: usize,
+    // a client whose pending output exceeds the hard limit, or stays above
+    // the soft limit for `output_soft_secs`, is disconnected
+    pub output_hard_limit: usize,
+    pub output_soft_limit: usize,
+    pub output_soft_secs: u64,
+    // the longest the event loop sleeps, in milliseconds
+    pub poll_timeout: u64,
+    pub aof_path: String,
+    pub aof_fsync: FsyncPolicy,
+    // trim a record cut short by a crash instead of refusing to start
+    pub aof_load_truncated: bool,
+    pub snapshot_path: String,
+    // the keyspace events published, in the letters of `notify::parse`
+    pub notify_keyspace_events: String,
+    // the memory limit in bytes, 0 for none
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    // how many random keys each eviction picks from
+    pub maxmemory_samples: usize,
+    // how many bytes of recent writes are kept for replicas to catch up
+    pub repl_backlog_size: usize,
+    // the user and password a replica authenticates to its primary with,
+    // no AUTH is sent without a password and no user means `default`
+    pub masteruser: String,
+    pub masterauth: String,
+    pub loglevel: Level,
+    // the users file read at startup and by ACL LOAD, empty for none
+    pub aclfile: String,
+}
+
+/// Parses a size in bytes such as `1024`, `64kb` or `1gb`. Like in redis,
+/// `k`, `m` and `g` are powers of 1000 and `kb`, `mb` and `gb` of 1024.
+fn parse_mem(s: &str) -> Option<usize> {
+    let s = s.to_ascii_lowercase();
+    let units = [
+        ("kb", 1 << 10),
+        ("mb", 1 << 20),
+        ("gb", 1 << 30),
+        ("k", 1000),
+        ("m", 1000 * 1000),
+        ("g", 1000 * 1000 * 1000),
+    ];
+    let (digits, unit) = units
+        .iter()
+        .find_map(|&(suffix, unit)| s.strip_suffix(suffix).map(|digits| (digits, unit)))
+        .unwrap_or((&s, 1));
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn yes_no(b: bool) -> String {
+    if b { "yes" } else { "no" }.to_string()
+}
+
+/// Quotes a value for the config file if it would not read back as is,
+/// escaping the `\\` and `"` in it.
+fn quote(value: &str) -> String {
+    if value.is_empty() || value.contains(char::is_whitespace) || value.contains('"') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Reads a value of the config file: in double quotes `\\` and `"` are
+/// escaped with a backslash, other backslashes are kept.
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(escaped @ ('\\' | '"'))) => {
+                out.push(escaped);
+                chars.next();
+            }
+            (c, _) => out.push(c),
+        }
+    }
+    out
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            file: None,
+            bind: vec![IpAddr::from([127, 0, 0, 1])],
+            port: 8080,
+            unixsocket: String::new(),
+            unixsocketperm: 0,
+            max_msg: 512 << 20,
+            max_query_buf: 1 << 30,
+            max_args: 1024,
+            output_hard_limit: 256 << 20,
+            output_soft_limit: 64 << 20,
+            output_soft_secs: 60,
+            poll_timeout: 1000,
+            aof_path: "appendonly.aof".to_string(),
+            aof_fsync: FsyncPolicy::EverySec,
+            aof_load_truncated: true,
+            snapshot_path: "dump.snap".to_string(),
+            notify_keyspace_events: String::new(),
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            maxmemory_samples: 5,
+            repl_backlog_size: 1 << 20,
+            masteruser: String::new(),
+            masterauth: String::new(),
+            loglevel: Level::Notice,
+            aclfile: String::new(),
+        }
+    }
+
+    /// Builds the settings from the command line arguments: a config file
+    /// optionally, then `--name value` pairs overriding it.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::new();
+        let mut args = args;
+        if let Some(path) = args.first().filter(|arg| !arg.starts_with("--")) {
+            config.load_file(path)?;
+            config.file = Some(path.clone());
+            args = &args[1..];
+        }
+        for pair in args.chunks(2) {
+            let name = pair[0]
+                .strip_prefix("--")
+                .ok_or_else(|| format!("expected an --option, got '{}'", pair[0]))?;
+            let value = pair
+                .get(1)
+                .ok_or_else(|| format!("--{name} needs a value"))?;
+            config
+                .set(name, value)
+                .map_err(|e| format!("--{name}: {e}"))?;
+        }
+        if config.port == 0 && config.unixsocket.is_empty() {
+            return Err("nothing to listen on, port is 0 and unixsocket is not set".to_string());
+        }
+        Ok(config)
+    }
+
+    /// Applies a config file of `name value` lines. Blank lines and lines
+    /// starting with `#` are skipped, a value may be in double quotes.
+    fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = unquote(value.trim());
+            self.set(name, &value)
+                .map_err(|e| format!("{path}:{}: {e}", i + 1))?;
+        }
+        Ok(())
+    }
+
+    /// Changes the setting `name`, checking that `value` is valid for it.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let bad = || format!("invalid value '{value}' for {name}");
+        let positive = |n: &usize| *n > 0;
+        match name {
+            "bind" => {
+                let addrs: Option<Vec<IpAddr>> = value
+                    .split_whitespace()
+                    .map(|addr| addr.parse().ok())
+                    .collect();
+                self.bind = addrs.filter(|addrs| !addrs.is_empty()).ok_or_else(bad)?;
+            }
+            "port" => self.port = value.parse().map_err(|_| bad())?,
+            "unixsocket" => self.unixsocket = value.to_string(),
+            "unixsocketperm" => {
+                self.unixsocketperm = u32::from_str_radix(value, 8)
+                    .ok()
+                    .filter(|&perm| perm <= 0o777)
+                    .ok_or_else(bad)?
+            }
+            "max-msg" => self.max_msg = parse_mem(value).filter(positive).ok_or_else(bad)?,
+            "max-query-buf" => {
+                self.max_query_buf = parse_mem(value).filter(positive).ok_or_else(bad)?
+            }
+            "max-args" => self.max_args = value.parse().ok().filter(positive).ok_or_else(bad)?,
+            "output-hard-limit" => self.output_hard_limit = parse_mem(value).ok_or_else(bad)?,
+            "output-soft-limit" => self.output_soft_limit = parse_mem(value).ok_or_else(bad)?,
+            "output-soft-seconds" => self.output_soft_secs = value.parse().map_err(|_| bad())?,
+            "poll-timeout" => {
+                self.poll_timeout = value.parse().ok().filter(|&ms| ms > 0).ok_or_else(bad)?
+            }
+            "appendfilename" if !value.is_empty() => self.aof_path = value.to_string(),
+            "appendfsync" => self.aof_fsync = FsyncPolicy::parse(value).ok_or_else(bad)?,
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value).ok_or_else(bad)?,
+            "dbfilename" if !value.is_empty() => self.snapshot_path = value.to_string(),
+            "appendfilename" | "dbfilename" => return Err(bad()),
+            "notify-keyspace-events" => {
+                notify::parse(value).ok_or_else(bad)?;
+                self.notify_keyspace_events = value.to_string();
+            }
+            "maxmemory" => self.maxmemory = parse_mem(value).ok_or_else(bad)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = EvictionPolicy::parse(value).ok_or_else(bad)?
+            }
+            "maxmemory-samples" => {
+                self.maxmemory_samples = value.parse().ok().filter(positive).ok_or_else(bad)?
+            }
+            "repl-backlog-size" => {
+                self.repl_backlog_size = parse_mem(value).filter(positive).ok_or_else(bad)?
+            }
+            "masteruser" => self.masteruser = value.to_string(),
+            "masterauth" => self.masterauth = value.to_string(),
+            "loglevel" => self.loglevel = Level::parse(value).ok_or_else(bad)?,
+            "aclfile" => self.aclfile = value.to_string(),
+            _ => return Err(format!("unknown option '{name}'")),
+        }
+        Ok(())
+    }
+
+    /// Returns the setting `name` the way it is written in a config file.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "bind" => {
+                let addrs: Vec<String> = self.bind.iter().map(IpAddr::to_string).collect();
+                addrs.join(" ")
+            }
+            "port" => self.port.to_string(),
+            "unixsocket" => self.unixsocket.clone(),
+            "unixsocketperm" => format!("{:o}", self.unixsocketperm),
+            "max-msg" => self.max_msg.to_string(),
+            "max-query-buf" => self.max_query_buf.to_string(),
+            "max-args" => self.max_args.to_string(),
+            "output-hard-limit" => self.output_hard_limit.to_string(),
+            "output-soft-limit" => self.output_soft_limit.to_string(),
+            "output-soft-seconds" => self.output_soft_secs.to_string(),
+            "poll-timeout" => self.poll_timeout.to_string(),
+            "appendfilename" => self.aof_path.clone(),
+            "appendfsync" => self.aof_fsync.name().to_string(),
+            "aof-load-truncated" => yes_no(self.aof_load_truncated),
+            "dbfilename" => self.snapshot_path.clone(),
+            "notify-keyspace-events" => self.notify_keyspace_events.clone(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.name().to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "repl-backlog-size" => self.repl_backlog_size.to_string(),
+            "masteruser" => self.masteruser.clone(),
+            "masterauth" => self.masterauth.clone(),
+            "loglevel" => self.loglevel.name().to_string(),
+            "aclfile" => self.aclfile.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Writes the settings back to the config file they were loaded from.
+    /// The comments and the order of the lines are kept: the settings in
+    /// the file are updated in place, the others are appended if they
+    /// differ from the defaults.
+    pub fn rewrite(&self) -> io::Result<()> {
+        let path = self
+            .file
+            .as_ref()
+            .ok_or_else(|| Error::other("the server is running without a config file"))?;
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let defaults = Config::new();
+        let mut written: Vec<&str> = Vec::new();
+        let mut lines: Vec<String> = Vec::new();
+        for line in text.lines() {
+            let name = line.split_whitespace().next().unwrap_or("");
+            if !NAMES.contains(&name) {
+                // comments, blank lines
+                lines.push(line.to_string());
+                continue;
+            }
+            // a setting given twice is only written once
+            if !written.contains(&name) {
+                written.push(name);
+                lines.push(format!("{name} {}", quote(&self.get(name).unwrap())));
+            }
+        }
+        for name in NAMES {
+            let value = self.get(name).unwrap();
+            if !written.contains(&name) && Some(&value) != defaults.get(name).as_ref() {
+                lines.push(format!("{name} {}", quote(&value)));
+            }
+        }
+
+        // written aside and renamed over, a crash leaves either file whole
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, lines.join("\n") + "\n")?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Hands the settings that live outside of the config to their
+    /// modules.
+    pub fn apply(&self) {
+        notify::set_flags(notify::parse(&self.notify_keyspace_events).unwrap());
+        log::set_level(self.loglevel);
+        if let Some(aof) = G_AOF.lock().unwrap().as_mut() {
+            aof.set_policy(self.aof_fsync);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        for value in [
+            "plain",
+            "",
+            "two words",
+            "a\\b",
+            "\"",
+            "say \"hi\"",
+            "\\\"",
+            "end\\",
+        ] {
+            assert_eq!(unquote(&quote(value)), value, "{value}");
+        }
+        assert_eq!(quote("a\\b"), "a\\b");
+        assert_eq!(quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+        // a backslash escaping nothing is kept
+        assert_eq!(unquote("\"a\\b c\""), "a\\b c");
+    }
+
+    #[test]
+    fn rewrite_reads_back() {
+        let path = std::env::temp_dir().join(format!("config-test-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "# kept\nport 7000\nmasterauth old\n").unwrap();
+
+        let mut config = Config::new();
+        config.load_file(&path).unwrap();
+        config.file = Some(path.clone());
+        config.set("masterauth", "p\"a s\\s\\").unwrap();
+        config.set("masteruser", "\"quoted\"").unwrap();
+        config.set("unixsocket", "/tmp/a b.sock").unwrap();
+        config.rewrite().unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# kept\nport 7000\nmasterauth "), "{text}");
+        let mut loaded = Config::new();
+        loaded.load_file(&path).unwrap();
+        for name in NAMES {
+            assert_eq!(loaded.get(name), config.get(name), "{name}");
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+/// Matches `s` against a glob-style `pattern` the way redis does:
+///
+/// - `*` matches any sequence, `?` any single byte
+/// - `[abc]`, `[a-z]` and `[^a]` match one byte from (or not from) a set;
+///   a `]` ends the set even right after the `[`, so `[]` matches nothing
+///   and `[\]]` is the set of `]`
+/// - `\x` matches `x` literally
+pub fn matches(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where to resume after the last `*`: the pattern after it, and the
+    // position in `s` it currently stands for the bytes up to
+    let mut star: Option<(usize, usize)> = None;
+    loop {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                star = Some((p + 1, i));
+                p += 1;
+                continue;
+            }
+            if i < s.len() {
+                if let Some(len) = match_one(&pattern[p..], s[i]) {
+                    p += len;
+                    i += 1;
+                    continue;
+                }
+            }
+        } else if i == s.len() {
+            return true;
+        }
+        // mismatch: let the last `*` swallow one more byte
+        match star {
+            Some((sp, si)) if si < s.len() => {
+                star = Some((sp, si + 1));
+                p = sp;
+                i = si + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Matches a single byte against the pattern element at the start of
+/// `pattern`, which is not `*`. Returns the length of the element on a match.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern[0] {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => {
+            let mut j = 1;
+            let negate = pattern.get(j) == Some(&b'^');
+            if negate {
+                j += 1;
+            }
+            let mut found = false;
+            while j < pattern.len() && pattern[j] != b']' {
+                if pattern[j] == b'\\' && j + 1 < pattern.len() {
+                    found |= pattern[j + 1] == c;
+                    j += 2;
+                } else if j + 2 < pattern.len() && pattern[j + 1] == b'-' && pattern[j + 2] != b']'
+                {
+                    let (lo, hi) = (
+                        pattern[j].min(pattern[j + 2]),
+                        pattern[j].max(pattern[j + 2]),
+                    );
+                    found |= (lo..=hi).contains(&c);
+                    j += 3;
+                } else {
+                    found |= pattern[j] == c;
+                    j += 1;
+                }
+            }
+            // an unterminated set runs to the end of the pattern
+            let len = (j + 1).min(pattern.len());
+            (found != negate).then_some(len)
+        }
+        b => (b == c).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    fn m(pattern: &str, s: &str) -> bool {
+        matches(pattern.as_bytes(), s.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(m("*", ""));
+        assert!(m("*", "anything"));
+        assert!(m("h?llo", "hello"));
+        assert!(!m("h?llo", "hllo"));
+        assert!(m("h*llo", "hllo"));
+        assert!(m("h*llo", "heeeello"));
+        assert!(m("*a*b*", "xxaxxbxx"));
+        assert!(!m("*a*b", "xxbxxa"));
+        assert!(m("user:*:name", "user:42:name"));
+        assert!(!m("user:*:name", "user:42:names"));
+        assert!(!m("abc", "abcd"));
+        assert!(!m("", "a"));
+        assert!(m("", ""));
+    }
+
+    #[test]
+    fn sets() {
+        assert!(m("h[ae]llo", "hallo"));
+        assert!(!m("h[ae]llo", "hillo"));
+        assert!(m("h[^e]llo", "hallo"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("h[a-c]llo", "hbllo"));
+        assert!(m("h[c-a]llo", "hbllo"));
+        assert!(!m("h[a-c]llo", "hdllo"));
+        // as in redis, unlike a shell, a `]` first in the set ends it
+        assert!(!m("[]]", "]"));
+        assert!(!m("[]", "a"));
+        assert!(m("[^]]", "a]"));
+        assert!(!m("[^]]", "a"));
+        assert!(m("[a-]", "-"));
+        assert!(m("[\\]]", "]"));
+        // an unterminated set runs to the end of the pattern
+        assert!(m("[ab", "b"));
+    }
+
+    #[test]
+    fn escapes_and_bytes() {
+        assert!(m("a\\*b", "a*b"));
+        assert!(!m("a\\*b", "axb"));
+        assert!(m("what\\?", "what?"));
+        assert!(matches(b"\xff*", b"\xff\x00\x01"));
+    }
+}
@@ -1,51 +1,48 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
 
 /// A node in the hash map.
-pub struct HNode {
-    next: Option<Rc<RefCell<HNode>>>,
+pub struct HNode<K, V> {
+    next: Link<K, V>,
     hcode: u64,
-
+    key: K,
+    val: V,
 }
 
+type Link<K, V> = Option<Box<HNode<K, V>>>;
 
-impl  HNode {
-    /// Creates a new `HNode` with the given `hcode`.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// let node = HNode::new(42);
-    /// ```
-    pub fn new(hcode: u64) -> Link {
-        Rc::new(RefCell::new(HNode {
-            next: None,
-            hcode: hcode,
-          
-
-        }))
-    }
+fn hash_of<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
 }
 
 /// A hash map with two tables for resizing.
-pub struct HMap {
-    ht1: Option<HTab>,
-    ht2: Option<HTab>,
+///
+/// When `ht1` gets too full it becomes `ht2` and a table twice as large
+/// takes its place. Every later insert, delete or mutable lookup then moves
+/// a bounded number of nodes from `ht2` to `ht1`, so no single operation
+/// pays for rehashing the whole map.
+pub struct HMap<K, V> {
+    ht1: Option<HTab<K, V>>,
+    ht2: Option<HTab<K, V>>,
     resizing_pos: usize,
 }
 
 const K_MAX_LOAD_FACTOR: usize = 8;
 const K_RESIZING_WORK: usize = 128;
 
-impl HMap {
+impl<K: Hash + Eq, V> HMap<K, V> {
     /// Creates a new, empty `HMap`.
     ///
     /// # Example
     ///
     /// ```
-    /// let map = HMap::new();
+    /// let map: HMap<String, u32> = HMap::new();
     /// ```
-    pub fn new() -> HMap {
+    pub fn new() -> HMap<K, V> {
         HMap {
             ht1: None,
             ht2: None,
@@ -53,29 +50,203 @@ impl HMap {
         }
     }
 
-    /// Inserts a new node into the hash map.
+    /// Inserts `key` with `val`, returning the previous value of the key.
     ///
     /// # Example
     ///
     /// ```
     /// let mut map = HMap::new();
-    /// let node = HNode::new(42);
-    /// map.hm_insert(node);
+    /// map.hm_insert("a".to_string(), 1);
+    /// assert_eq!(map.hm_insert("a".to_string(), 2), Some(1));
     /// ```
-    pub fn hm_insert(&mut self, node: Link) {
+    pub fn hm_insert(&mut self, key: K, val: V) -> Option<V> {
+        self.help_resizing();
+        let hcode = hash_of(&key);
+        if let Some(node) = self.lookup_node_mut(&key, hcode) {
+            return Some(mem::replace(&mut node.val, val));
+        }
+
         if self.ht1.is_none() {
             self.ht1 = Some(HTab::new(4));
         }
         let htab = self.ht1.as_mut().unwrap();
-        htab.insert(node);
+        htab.insert(Box::new(HNode {
+            next: None,
+            hcode,
+            key,
+            val,
+        }));
 
         if self.ht2.is_none() {
-            let load_factor = self.ht1.as_ref().unwrap().size / (self.ht1.as_ref().unwrap().mask + 1);
+            let load_factor = htab.size / (htab.mask + 1);
             if load_factor > K_MAX_LOAD_FACTOR {
                 self.start_resizing();
             }
         }
+        None
+    }
+
+    /// Looks up the value of `key`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut map = HMap::new();
+    /// map.hm_insert("a".to_string(), 1);
+    /// assert_eq!(map.hm_lookup("a"), Some(&1));
+    /// ```
+    pub fn hm_lookup<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hcode = hash_of(key);
+        [&self.ht1, &self.ht2]
+            .into_iter()
+            .flatten()
+            .find_map(|htab| htab.h_lookup(key, hcode))
+            .map(|node| &node.val)
+    }
+
+    /// Looks up the value of `key` for modification.
+    pub fn hm_lookup_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         self.help_resizing();
+        let hcode = hash_of(key);
+        self.lookup_node_mut(key, hcode).map(|node| &mut node.val)
+    }
+
+    fn lookup_node_mut<Q>(&mut self, key: &Q, hcode: u64) -> Option<&mut HNode<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        [&mut self.ht1, &mut self.ht2]
+            .into_iter()
+            .flatten()
+            .find_map(|htab| htab.h_lookup_slot(key, hcode))
+            .and_then(|slot| slot.as_deref_mut())
+    }
+
+    /// Removes `key` from the map, returning the key and its value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut map = HMap::new();
+    /// map.hm_insert("a".to_string(), 1);
+    /// assert_eq!(map.hm_delete("a"), Some(("a".to_string(), 1)));
+    /// ```
+    pub fn hm_delete<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.help_resizing();
+        let hcode = hash_of(key);
+        for htab in [&mut self.ht1, &mut self.ht2].into_iter().flatten() {
+            if let Some(node) = htab.delete(key, hcode) {
+                return Some((node.key, node.val));
+            }
+        }
+        None
+    }
+
+    /// Returns the number of entries.
+    pub fn hm_size(&self) -> usize {
+        [&self.ht1, &self.ht2]
+            .into_iter()
+            .flatten()
+            .map(|htab| htab.size)
+            .sum()
+    }
+
+    /// Visits some of the entries, starting at `cursor`, and returns the
+    /// cursor to continue from, 0 once everything was visited.
+    ///
+    /// The cursor counts buckets with its bits reversed, so an entry present
+    /// for a whole scan is visited at least once even if the map resizes in
+    /// between calls. Entries may be visited more than once.
+    pub fn hm_scan<'a, F>(&'a self, mut cursor: u64, mut f: F) -> u64
+    where
+        F: FnMut(&'a K, &'a V),
+    {
+        let mut visit = |htab: &'a HTab<K, V>, pos: u64| {
+            let slot = &htab.table[pos as usize & htab.mask];
+            for node in (ChainIter {
+                cur: slot.as_deref(),
+            }) {
+                f(&node.key, &node.val);
+            }
+        };
+        let next = |cursor: u64, mask: u64| {
+            (cursor | !mask)
+                .reverse_bits()
+                .wrapping_add(1)
+                .reverse_bits()
+        };
+
+        match (&self.ht1, &self.ht2) {
+            (None, _) => 0,
+            (Some(htab), None) => {
+                visit(htab, cursor);
+                next(cursor, htab.mask as u64)
+            }
+            // while resizing `ht2` is the smaller table; every bucket of it
+            // expands to the buckets of `ht1` that share its low bits
+            (Some(large), Some(small)) => {
+                let (m0, m1) = (small.mask as u64, large.mask as u64);
+                visit(small, cursor);
+                loop {
+                    visit(large, cursor);
+                    cursor = next(cursor, m1);
+                    if cursor & (m0 ^ m1) == 0 {
+                        break;
+                    }
+                }
+                cursor
+            }
+        }
+    }
+
+    /// Picks an entry using the random number `r`: a random bucket, or the
+    /// first non-empty one after it, then a random node of its chain. Not
+    /// uniform, entries in short chains are picked more often.
+    pub fn hm_random(&self, r: u64) -> Option<(&K, &V)> {
+        if self.hm_size() == 0 {
+            return None;
+        }
+        let tables: Vec<&HTab<K, V>> = [&self.ht1, &self.ht2]
+            .into_iter()
+            .flatten()
+            .filter(|htab| htab.size > 0)
+            .collect();
+        let htab = tables[(r >> 32) as usize % tables.len()];
+        let start = r as usize & htab.mask;
+        let slot = (0..=htab.mask)
+            .map(|i| &htab.table[(start + i) & htab.mask])
+            .find(|slot| slot.is_some())?;
+        let chain: Vec<&HNode<K, V>> = ChainIter {
+            cur: slot.as_deref(),
+        }
+        .collect();
+        let node = chain[(r >> 16) as usize % chain.len()];
+        Some((&node.key, &node.val))
+    }
+
+    /// Iterates over all entries in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        [&self.ht1, &self.ht2]
+            .into_iter()
+            .flatten()
+            .flat_map(|htab| htab.table.iter())
+            .flat_map(|slot| ChainIter {
+                cur: slot.as_deref(),
+            })
+            .map(|node| (&node.key, &node.val))
     }
 
     /// Starts the resizing process by creating a new table and moving nodes from the old table.
@@ -88,124 +259,236 @@ impl HMap {
 
     /// Helps the resizing process by moving nodes from the old table to the new table.
     fn help_resizing(&mut self) {
+        let (Some(tab1), Some(tab2)) = (self.ht1.as_mut(), self.ht2.as_mut()) else {
+            return;
+        };
         let mut nwork = 0;
-        let tab2 = self.ht2.as_mut().unwrap();
-        let tab1 = self.ht1.as_mut().unwrap();
+        // empty buckets count as work too, so a sparse table can't stall us
         while nwork < K_RESIZING_WORK && tab2.size > 0 {
-            let from = tab2.table[self.resizing_pos].take();
-            if from.is_none() {
-                self.resizing_pos += 1;
-                continue;
-            }
-            tab1.insert(from.expect("wtf happen?"));
             nwork += 1;
+            match h_detach(&mut tab2.table[self.resizing_pos]) {
+                None => self.resizing_pos += 1,
+                Some(node) => {
+                    tab2.size -= 1;
+                    tab1.insert(node);
+                }
+            }
         }
 
-        if tab2.size == 0 && self.ht2.is_some() {
-            let _ = self.ht2.take();
+        if tab2.size == 0 {
+            self.ht2 = None;
         }
     }
-
-
-    fn hm_lookup(&mut self,node:Link,comparator:Comparator)->Option<Link>{
-        self.help_resizing();
-        let mut from=self.ht1.as_ref().unwrap().h_lookup(node.clone(), comparator);
-        if from.is_none(){
-            from=self.ht2.as_ref().unwrap().h_lookup(node.clone(), comparator)
-        };
-        return from;
-
-    }
 }
 
 /// A hash table with a vector of nodes.
-struct HTab {
-    table: Vec<Option<Link>>,
+struct HTab<K, V> {
+    table: Vec<Link<K, V>>,
     mask: usize,
     size: usize,
 }
 
-pub type Link = Rc<RefCell<HNode>>;
-type Comparator=fn(Link, Link) -> bool ;
-
-
-
-impl HTab {
+impl<K: Eq, V> HTab<K, V> {
     /// Creates a new `HTab` with the given `size`.
     ///
     /// # Example
     ///
     /// ```
-    /// let tab = HTab::new(4);
+    /// let tab: HTab<String, u32> = HTab::new(4);
     /// ```
     fn new(size: usize) -> Self {
         assert!(size > 0 && ((size - 1) & size) == 0);
         HTab {
-            table: vec![None; size],
+            table: (0..size).map(|_| None).collect(),
             mask: size - 1,
             size: 0,
         }
     }
 
     /// Inserts a new node into the hash table.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// let mut tab = HTab::new(4);
-    /// let node = HNode::new(42);
-    /// tab.insert(node);
-    /// ```
-    fn insert(&mut self, node: Link) {
-        let hcode = node.borrow().hcode;
-        let pos = (hcode as usize) & self.mask;
-        let prev = self.table[pos].take();
-        node.borrow_mut().next = prev;
+    fn insert(&mut self, mut node: Box<HNode<K, V>>) {
+        let pos = (node.hcode as usize) & self.mask;
+        node.next = self.table[pos].take();
         self.table[pos] = Some(node);
         self.size += 1;
     }
 
-    /// Looks up a node in the hash table using a custom comparator.
-    ///
-    ///# Example
-    ///
-    /// ```
-    /// let mut tab = HTab::new(4);
-    /// let node = HNode::new(42);
-    /// tab.insert(node.clone());
-    /// let found = tab.h_lookup(node, |a, b| a.borrow().hcode == b.borrow().hcode);
-    /// assert!(found.is_some());
-    /// ```
-    fn h_lookup(&self, node: Link, comparator: Comparator)-> Option<Link> {
-        let hcode = node.borrow().hcode;
+    /// Looks up the node holding `key`.
+    fn h_lookup<Q>(&self, key: &Q, hcode: u64) -> Option<&HNode<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
         let pos = (hcode as usize) & self.mask;
-        let mut cur = self.table[pos].clone();
-        while let Some(ref cur_node) = cur {
-            if comparator(node.clone(), cur_node.clone()) {
-                return Some(cur_node.clone());
+        let mut cur = self.table[pos].as_deref();
+        while let Some(node) = cur {
+            if node.hcode == hcode && node.key.borrow() == key {
+                return Some(node);
             }
+            cur = node.next.as_deref();
         }
-        return None;
+        None
     }
 
-    /// Detaches a node from the hash table.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// let mut tab = HTab::new(4);
-    /// let node = HNode::new(42);
-    /// tab.insert(node.clone());
-    /// let mut from = Some(node.clone());
-    /// let detached = tab.h_detach(&mut from);
-    /// assert!(detached.is_some());
-    /// ```
-    fn h_detach(&mut self, from: &mut Option<Rc<RefCell<HNode>>>) -> Option<Rc<RefCell<HNode>>> {
-        let node = from.take()?; // Take ownership of the node if it exists
-        *from = node.borrow().next.clone(); // Update 'from' to point to the next node
-        self.size -= 1; // Decrease the size of the hash table
-        Some(node) // Return the detached node
+    /// Looks up the link pointing to the node holding `key`, so the node can
+    /// be modified or detached.
+    fn h_lookup_slot<Q>(&mut self, key: &Q, hcode: u64) -> Option<&mut Link<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let pos = (hcode as usize) & self.mask;
+        let mut from = &mut self.table[pos];
+        while from
+            .as_ref()
+            .is_some_and(|node| node.hcode != hcode || node.key.borrow() != key)
+        {
+            from = &mut from.as_mut().unwrap().next;
+        }
+        from.is_some().then_some(from)
+    }
+
+    /// Removes the node holding `key` from the table.
+    fn delete<Q>(&mut self, key: &Q, hcode: u64) -> Option<Box<HNode<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let node = h_detach(self.h_lookup_slot(key, hcode)?)?;
+        self.size -= 1;
+        Some(node)
+    }
+}
+
+/// Detaches a node from its chain.
+///
+/// `from` is the link pointing to the node; afterwards it points to the
+/// node that followed. The caller is responsible for the table size.
+fn h_detach<K, V>(from: &mut Link<K, V>) -> Option<Box<HNode<K, V>>> {
+    let mut node = from.take()?;
+    *from = node.next.take();
+    Some(node)
+}
+
+/// Walks a single bucket chain.
+struct ChainIter<'a, K, V> {
+    cur: Option<&'a HNode<K, V>>,
+}
+
+impl<'a, K, V> Iterator for ChainIter<'a, K, V> {
+    type Item = &'a HNode<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.cur?;
+        self.cur = node.next.as_deref();
+        Some(node)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_lookup_delete() {
+        let mut map = HMap::new();
+        assert_eq!(map.hm_lookup("a"), None);
+        assert_eq!(map.hm_insert("a".to_string(), 1), None);
+        assert_eq!(map.hm_insert("a".to_string(), 2), Some(1));
+        *map.hm_lookup_mut("a").unwrap() += 1;
+        assert_eq!(map.hm_lookup("a"), Some(&3));
+        assert_eq!(map.hm_size(), 1);
+        assert_eq!(map.hm_delete("a"), Some(("a".to_string(), 3)));
+        assert_eq!(map.hm_delete("a"), None);
+        assert_eq!(map.hm_size(), 0);
+    }
 
+    #[test]
+    fn entries_survive_incremental_rehash() {
+        let mut map = HMap::new();
+        let mut resized = false;
+        for i in 0..20000u32 {
+            map.hm_insert(i, i * 2);
+            resized |= map.ht2.is_some();
+            // every key stays reachable while it is moved between tables
+            if i % 97 == 0 {
+                for j in (0..=i).step_by(31) {
+                    assert_eq!(map.hm_lookup(&j), Some(&(j * 2)));
+                }
+            }
+        }
+        assert!(resized);
+        assert_eq!(map.hm_size(), 20000);
+        assert_eq!(map.iter().count(), 20000);
+
+        for i in (0..20000u32).filter(|i| i % 2 == 0) {
+            assert_eq!(map.hm_delete(&i), Some((i, i * 2)));
+        }
+        assert_eq!(map.hm_size(), 10000);
+        for i in 0..20000u32 {
+            let expected = (i % 2 == 1).then_some(i * 2);
+            assert_eq!(map.hm_lookup(&i).copied(), expected);
+        }
+    }
+
+    /// Scans `map` to the end, running `between` after every call.
+    fn scan_all(map: &mut HMap<u32, ()>, mut between: impl FnMut(&mut HMap<u32, ()>)) -> Vec<u32> {
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            cursor = map.hm_scan(cursor, |key, _| seen.push(*key));
+            if cursor == 0 {
+                return seen;
+            }
+            between(map);
+        }
+    }
+
+    #[test]
+    fn scan_visits_everything() {
+        let mut map = HMap::new();
+        assert_eq!(scan_all(&mut map, |_| {}), []);
+        for i in 0..500u32 {
+            map.hm_insert(i, ());
+        }
+        let mut seen = scan_all(&mut map, |_| {});
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen, (0..500).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn scan_survives_resizing() {
+        let mut map = HMap::new();
+        for i in 0..300u32 {
+            map.hm_insert(i, ());
+        }
+        // the map grows, and rehashes, while the scan is under way
+        let mut next = 300u32;
+        let mut seen = scan_all(&mut map, |map| {
+            for _ in 0..50 {
+                map.hm_insert(next, ());
+                next += 1;
+            }
+        });
+        assert!(next > 1000);
+        seen.sort();
+        seen.dedup();
+        // the keys there from start to end were all visited
+        assert!((0..300).all(|i| seen.binary_search(&i).is_ok()));
+    }
+
+    #[test]
+    fn rehash_finishes() {
+        let mut map = HMap::new();
+        for i in 0..1000u32 {
+            map.hm_insert(i, ());
+        }
+        // lookups for modification move nodes along too
+        for _ in 0..1000 {
+            map.hm_lookup_mut(&0);
+        }
+        assert!(map.ht2.is_none());
+        assert_eq!(map.ht1.as_ref().unwrap().size, 1000);
+    }
+}
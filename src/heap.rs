@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+/// An item in the heap: a deadline in unix milliseconds and the key it
+/// belongs to.
+struct HeapItem {
+    deadline: u64,
+    key: Vec<u8>,
+}
+
+/// A binary min-heap of key deadlines. The position of every key in the
+/// heap is tracked so a deadline can be updated or removed in O(log n).
+pub struct TtlHeap {
+    items: Vec<HeapItem>,
+    pos: HashMap<Vec<u8>, usize>,
+}
+
+impl TtlHeap {
+    pub fn new() -> TtlHeap {
+        TtlHeap {
+            items: Vec::new(),
+            pos: HashMap::new(),
+        }
+    }
+
+    /// Returns the deadline of `key`, if it has one.
+    pub fn get(&self, key: &[u8]) -> Option<u64> {
+        self.pos.get(key).map(|&i| self.items[i].deadline)
+    }
+
+    /// Sets or updates the deadline of `key`.
+    pub fn set(&mut self, key: &[u8], deadline: u64) {
+        match self.pos.get(key) {
+            Some(&i) => {
+                self.items[i].deadline = deadline;
+                self.fix(i);
+            }
+            None => {
+                let i = self.items.len();
+                self.items.push(HeapItem {
+                    deadline,
+                    key: key.to_vec(),
+                });
+                self.pos.insert(key.to_vec(), i);
+                self.up(i);
+            }
+        }
+    }
+
+    /// Removes the deadline of `key` and returns it.
+    pub fn remove(&mut self, key: &[u8]) -> Option<u64> {
+        let i = self.pos.remove(key)?;
+        let last = self.items.len() - 1;
+        self.items.swap(i, last);
+        let item = self.items.pop().unwrap();
+        if i < self.items.len() {
+            self.pos.insert(self.items[i].key.clone(), i);
+            self.fix(i);
+        }
+        Some(item.deadline)
+    }
+
+    /// Returns the earliest deadline and its key.
+    pub fn peek(&self) -> Option<(u64, &[u8])> {
+        self.items
+            .first()
+            .map(|item| (item.deadline, item.key.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns a key picked by the random number `r`.
+    pub fn random(&self, r: u64) -> Option<&[u8]> {
+        if self.items.is_empty() {
+            return None;
+        }
+        Some(&self.items[r as usize % self.items.len()].key)
+    }
+
+    /// Restores the heap order for an item whose deadline has changed.
+    fn fix(&mut self, i: usize) {
+        if i > 0 && self.items[i].deadline < self.items[(i - 1) / 2].deadline {
+            self.up(i);
+        } else {
+            self.down(i);
+        }
+    }
+
+    fn swap(&mut self, i: usize, j: usize) {
+        self.items.swap(i, j);
+        *self.pos.get_mut(&self.items[i].key).unwrap() = i;
+        *self.pos.get_mut(&self.items[j].key).unwrap() = j;
+    }
+
+    fn up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.items[parent].deadline <= self.items[i].deadline {
+                break;
+            }
+            self.swap(i, parent);
+            i = parent;
+        }
+    }
+
+    fn down(&mut self, mut i: usize) {
+        loop {
+            let (l, r) = (2 * i + 1, 2 * i + 2);
+            let mut min = i;
+            if l < self.items.len() && self.items[l].deadline < self.items[min].deadline {
+                min = l;
+            }
+            if r < self.items.len() && self.items[r].deadline < self.items[min].deadline {
+                min = r;
+            }
+            if min == i {
+                break;
+            }
+            self.swap(i, min);
+            i = min;
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::keyspace::now_ms;
+use crate::snapshot;
+use crate::{not_counted_memory, used_memory, G_AOF, G_CONFIG, G_MAP, G_REPL};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Unix time in milliseconds the server started at.
+pub static START_MS: AtomicU64 = AtomicU64::new(0);
+/// The number of connections in the event loop, kept up to date by it.
+pub static CONNECTED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+/// The number of connections ever accepted.
+pub static TOTAL_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+/// GETs of a key that exists, and of one that does not.
+pub static KEYSPACE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static KEYSPACE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// The calls of a command and the time spent running them.
+#[derive(Clone, Copy, Default)]
+struct CommandStats {
+    calls: u64,
+    usec: u64,
+}
+
+static COMMANDS: Mutex<BTreeMap<String, CommandStats>> = Mutex::new(BTreeMap::new());
+
+// the sections of a plain INFO, and those of INFO ALL
+const DEFAULT: [&str; 7] = [
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "keyspace",
+];
+const ALL: [&str; 8] = [
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "commandstats",
+    "keyspace",
+];
+
+/// Records a run of the command `name` that took `usec` microseconds.
+pub fn record_command(name: &str, usec: u64) {
+    let mut commands = COMMANDS.lock().unwrap();
+    let stats = commands.entry(name.to_ascii_lowercase()).or_default();
+    stats.calls += 1;
+    stats.usec += usec;
+}
+
+/// Formats a byte count the way redis' `*_human` fields do, e.g. `1.50M`.
+fn human(bytes: usize) -> String {
+    let units = [(1 << 30, 'G'), (1 << 20, 'M'), (1 << 10, 'K')];
+    match units.iter().find(|(size, _)| bytes >= *size) {
+        Some((size, unit)) => format!("{:.2}{unit}", bytes as f64 / *size as f64),
+        None => format!("{bytes}B"),
+    }
+}
+
+/// Returns the `key:value` lines of one section, `None` for an unknown
+/// one.
+fn section(name: &str) -> Option<String> {
+    let mut s = String::new();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    match name {
+        "server" => {
+            let config = G_CONFIG.read().unwrap();
+            let uptime = now_ms().saturating_sub(load(&START_MS)) / 1000;
+            writeln!(s, "# Server\r").unwrap();
+            writeln!(s, "rustis_version:{}\r", env!("CARGO_PKG_VERSION")).unwrap();
+            writeln!(s, "process_id:{}\r", std::process::id()).unwrap();
+            writeln!(s, "tcp_port:{}\r", config.port).unwrap();
+            writeln!(s, "uptime_in_seconds:{uptime}\r").unwrap();
+            writeln!(s, "uptime_in_days:{}\r", uptime / 86400).unwrap();
+            writeln!(s, "config_file:{}\r", config.file.as_deref().unwrap_or("")).unwrap();
+        }
+        "clients" => {
+            writeln!(s, "# Clients\r").unwrap();
+            writeln!(s, "connected_clients:{}\r", load(&CONNECTED_CLIENTS)).unwrap();
+        }
+        "memory" => {
+            let config = G_CONFIG.read().unwrap();
+            let used = used_memory();
+            writeln!(s, "# Memory\r").unwrap();
+            writeln!(s, "used_memory:{used}\r").unwrap();
+            writeln!(s, "used_memory_human:{}\r", human(used)).unwrap();
+            writeln!(s, "mem_not_counted_for_evict:{}\r", not_counted_memory()).unwrap();
+            writeln!(s, "maxmemory:{}\r", config.maxmemory).unwrap();
+            writeln!(s, "maxmemory_human:{}\r", human(config.maxmemory)).unwrap();
+            writeln!(s, "maxmemory_policy:{}\r", config.maxmemory_policy.name()).unwrap();
+        }
+        "persistence" => {
+            let running = snapshot::BGSAVE_RUNNING.load(Ordering::Acquire);
+            let last_save = snapshot::LAST_SAVE_MS.load(Ordering::Acquire) / 1000;
+            let last_ok = snapshot::LAST_SAVE_OK.load(Ordering::Acquire);
+            let aof_size = G_AOF.lock().unwrap().as_ref().map(|aof| aof.len());
+            writeln!(s, "# Persistence\r").unwrap();
+            writeln!(s, "rdb_bgsave_in_progress:{}\r", running as u8).unwrap();
+            writeln!(s, "rdb_last_save_time:{last_save}\r").unwrap();
+            let status = if last_ok { "ok" } else { "err" };
+            writeln!(s, "rdb_last_bgsave_status:{status}\r").unwrap();
+            writeln!(s, "aof_enabled:{}\r", aof_size.is_some() as u8).unwrap();
+            writeln!(s, "aof_current_size:{}\r", aof_size.unwrap_or(0)).unwrap();
+        }
+        "stats" => {
+            let (evicted, expired) = {
+                let map = G_MAP.lock().unwrap();
+                (map.evicted_keys(), map.expired_keys())
+            };
+            let commands = COMMANDS.lock().unwrap();
+            let total: u64 = commands.values().map(|stats| stats.calls).sum();
+            let (hits, misses) = (load(&KEYSPACE_HITS), load(&KEYSPACE_MISSES));
+            let ratio = if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            };
+            writeln!(s, "# Stats\r").unwrap();
+            writeln!(
+                s,
+                "total_connections_received:{}\r",
+                load(&TOTAL_CONNECTIONS)
+            )
+            .unwrap();
+            writeln!(s, "total_commands_processed:{total}\r").unwrap();
+            writeln!(s, "keyspace_hits:{hits}\r").unwrap();
+            writeln!(s, "keyspace_misses:{misses}\r").unwrap();
+            writeln!(s, "keyspace_hit_ratio:{ratio:.4}\r").unwrap();
+            writeln!(s, "expired_keys:{expired}\r").unwrap();
+            writeln!(s, "evicted_keys:{evicted}\r").unwrap();
+        }
+        "replication" => {
+            let repl = G_REPL.lock().unwrap();
+            writeln!(s, "# Replication\r").unwrap();
+            match &repl.primary {
+                None => {
+                    writeln!(s, "role:master\r").unwrap();
+                    writeln!(s, "connected_slaves:{}\r", repl.replicas.len()).unwrap();
+                    writeln!(s, "master_replid:{}\r", repl.replid).unwrap();
+                    writeln!(s, "master_repl_offset:{}\r", repl.offset()).unwrap();
+                }
+                Some((host, port)) => {
+                    let status = if repl.link_synced { "up" } else { "down" };
+                    writeln!(s, "role:slave\r").unwrap();
+                    writeln!(s, "master_host:{host}\r").unwrap();
+                    writeln!(s, "master_port:{port}\r").unwrap();
+                    writeln!(s, "master_link_status:{status}\r").unwrap();
+                    writeln!(s, "slave_repl_offset:{}\r", repl.primary_offset).unwrap();
+                }
+            }
+        }
+        "commandstats" => {
+            writeln!(s, "# Commandstats\r").unwrap();
+            for (name, stats) in COMMANDS.lock().unwrap().iter() {
+                let per_call = stats.usec as f64 / stats.calls as f64;
+                writeln!(
+                    s,
+                    "cmdstat_{name}:calls={},usec={},usec_per_call={per_call:.2}\r",
+                    stats.calls, stats.usec
+                )
+                .unwrap();
+            }
+        }
+        "keyspace" => {
+            let map = G_MAP.lock().unwrap();
+            writeln!(s, "# Keyspace\r").unwrap();
+            if map.len() > 0 {
+                writeln!(s, "db0:keys={},expires={}\r", map.len(), map.expires()).unwrap();
+            }
+        }
+        _ => return None,
+    }
+    Some(s)
+}
+
+/// Returns the INFO report of `sections`: `default` (also for none),
+/// `all` / `everything`, or section names. Unknown sections are left out.
+pub fn info(sections: &[String]) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    let default = [String::from("default")];
+    for name in if sections.is_empty() {
+        &default[..]
+    } else {
+        sections
+    } {
+        match name.as_str() {
+            "default" => names.extend(DEFAULT),
+            "all" | "everything" => names.extend(ALL),
+            name => names.extend(ALL.iter().find(|section| **section == name)),
+        }
+    }
+    // in the order of ALL, each once
+    let sections: Vec<String> = ALL
+        .iter()
+        .filter(|name| names.contains(name))
+        .filter_map(|name| section(name))
+        .collect();
+    sections.join("\r\n")
+}
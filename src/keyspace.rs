@@ -0,0 +1,368 @@
+use crate::hashtable::HMap;
+use crate::heap::TtlHeap;
+use crate::notify;
+use crate::set::rand_u64;
+use crate::Value;
+use mio::Token;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the access count of a new key, so that it is not evicted first thing
+const K_LFU_INIT: u8 = 5;
+// how much harder each increment of the access count gets
+const K_LFU_LOG_FACTOR: f64 = 10.0;
+
+/// Returns the current unix time in milliseconds.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Which keys are evicted once the memory limit is reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    /// Nothing, writes that need memory fail instead.
+    NoEviction,
+    /// The least recently used key.
+    AllKeysLru,
+    /// The least frequently used key.
+    AllKeysLfu,
+    /// Any key.
+    AllKeysRandom,
+    /// The least recently used key with a deadline.
+    VolatileLru,
+    /// The key with the nearest deadline.
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Parses a `maxmemory-policy` name, see `name`.
+    pub fn parse(s: &str) -> Option<EvictionPolicy> {
+        [
+            EvictionPolicy::NoEviction,
+            EvictionPolicy::AllKeysLru,
+            EvictionPolicy::AllKeysLfu,
+            EvictionPolicy::AllKeysRandom,
+            EvictionPolicy::VolatileLru,
+            EvictionPolicy::VolatileTtl,
+        ]
+        .into_iter()
+        .find(|policy| policy.name() == s)
+    }
+
+    /// The name of the policy in redis' `maxmemory-policy`.
+    pub fn name(self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+/// A value along with how it has been accessed, for eviction.
+struct Entry {
+    val: Value,
+    // unix time in seconds of the last access
+    access: Cell<u32>,
+    // a logarithmic access count, decayed by one per idle minute
+    freq: Cell<u8>,
+}
+
+impl Entry {
+    fn new(val: Value) -> Entry {
+        Entry {
+            val,
+            access: Cell::new((now_ms() / 1000) as u32),
+            freq: Cell::new(K_LFU_INIT),
+        }
+    }
+
+    /// Returns the access count decayed to `secs`.
+    fn decayed_freq(&self, secs: u32) -> u8 {
+        let idle_mins = secs.saturating_sub(self.access.get()) / 60;
+        self.freq.get().saturating_sub(idle_mins.min(255) as u8)
+    }
+
+    /// Records an access at `now` in unix milliseconds. The count goes up
+    /// with a probability that shrinks as it grows, like in redis, so that
+    /// a byte covers millions of accesses.
+    fn accessed(&self, now: u64) {
+        let secs = (now / 1000) as u32;
+        let mut freq = self.decayed_freq(secs);
+        if freq < u8::MAX {
+            let base = freq.saturating_sub(K_LFU_INIT) as f64;
+            let p = 1.0 / (base * K_LFU_LOG_FACTOR + 1.0);
+            if (rand_u64() as f64) < p * u64::MAX as f64 {
+                freq += 1;
+            }
+        }
+        self.freq.set(freq);
+        self.access.set(secs);
+    }
+}
+
+/// The keyspace: the key to value map plus the deadlines of volatile keys.
+///
+/// Expired keys are collected in batches by `expire_due` from the event
+/// loop; until then every lookup treats them as missing.
+///
+/// It also tracks the keys WATCHed by connections: any change to one, an
+/// expiration included, marks its watchers dirty so their EXEC fails.
+pub struct Keyspace {
+    map: HMap<Vec<u8>, Entry>,
+    ttl: TtlHeap,
+    watchers: HashMap<Vec<u8>, HashSet<Token>>,
+    dirty: HashSet<Token>,
+    evicted_keys: u64,
+    expired_keys: u64,
+}
+
+impl Keyspace {
+    pub fn new() -> Keyspace {
+        Keyspace {
+            map: HMap::new(),
+            ttl: TtlHeap::new(),
+            watchers: HashMap::new(),
+            dirty: HashSet::new(),
+            evicted_keys: 0,
+            expired_keys: 0,
+        }
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> bool {
+        self.ttl.get(key).is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Deletes `key` if it has expired, so that writers see it as missing.
+    fn purge(&mut self, key: &[u8]) {
+        if self.is_expired(key, now_ms()) {
+            self.ttl.remove(key);
+            self.map.hm_delete(key);
+            self.expired_keys += 1;
+            self.touch(key);
+            notify::notify(notify::EXPIRED, "expired", key);
+        }
+    }
+
+    /// Marks the watchers of `key` dirty.
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(tokens) = self.watchers.get(key) {
+            self.dirty.extend(tokens);
+        }
+    }
+
+    pub fn watch(&mut self, token: Token, key: &[u8]) {
+        self.watchers.entry(key.to_vec()).or_default().insert(token);
+    }
+
+    /// Stops watching `keys` and forgets whether they were modified.
+    pub fn unwatch(&mut self, token: Token, keys: &[Vec<u8>]) {
+        for key in keys {
+            if let Some(tokens) = self.watchers.get_mut(key) {
+                tokens.remove(&token);
+                if tokens.is_empty() {
+                    self.watchers.remove(key);
+                }
+            }
+        }
+        self.dirty.remove(&token);
+    }
+
+    /// Replaces every key with those of `other`, which modifies all the
+    /// watched keys.
+    pub fn replace(&mut self, other: Keyspace) {
+        self.map = other.map;
+        self.ttl = other.ttl;
+        self.dirty.extend(self.watchers.values().flatten());
+    }
+
+    /// Returns `true` if a key watched by `token` was modified.
+    pub fn is_dirty(&self, token: Token) -> bool {
+        self.dirty.contains(&token)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        let now = now_ms();
+        if self.is_expired(key, now) {
+            return None;
+        }
+        let entry = self.map.hm_lookup(key)?;
+        entry.accessed(now);
+        Some(&entry.val)
+    }
+
+    /// Returns the value of `key` for modifying it, so its watchers are
+    /// marked dirty even if the caller ends up changing nothing.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Value> {
+        self.purge(key);
+        self.touch(key);
+        let entry = self.map.hm_lookup_mut(key)?;
+        entry.accessed(now_ms());
+        Some(&mut entry.val)
+    }
+
+    /// Returns the value of `key`, inserting the one built by `f` if the key
+    /// is missing.
+    pub fn get_or_insert_with<F: FnOnce() -> Value>(&mut self, key: &[u8], f: F) -> &mut Value {
+        self.purge(key);
+        self.touch(key);
+        if self.map.hm_lookup(key).is_none() {
+            self.map.hm_insert(key.to_vec(), Entry::new(f()));
+            notify::notify(notify::NEW, "new", key);
+        }
+        let entry = self.map.hm_lookup_mut(key).unwrap();
+        entry.accessed(now_ms());
+        &mut entry.val
+    }
+
+    /// Stores `val` under `key`, dropping any previous value and deadline.
+    pub fn insert(&mut self, key: &[u8], val: Value) {
+        self.ttl.remove(key);
+        self.touch(key);
+        if self.map.hm_insert(key.to_vec(), Entry::new(val)).is_none() {
+            notify::notify(notify::NEW, "new", key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        self.purge(key);
+        self.ttl.remove(key);
+        let val = self.map.hm_delete(key).map(|(_, entry)| entry.val);
+        if val.is_some() {
+            self.touch(key);
+        }
+        val
+    }
+
+    /// Iterates over the keys that have not expired.
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.iter().map(|(key, _, _)| key)
+    }
+
+    /// Iterates over the entries that have not expired, along with their
+    /// deadlines.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Value, Option<u64>)> {
+        let now = now_ms();
+        self.map
+            .iter()
+            .filter(move |(key, _)| !self.is_expired(key, now))
+            .map(|(key, entry)| (key, &entry.val, self.ttl.get(key)))
+    }
+
+    /// Returns the deadline of `key` in unix milliseconds, if it has one.
+    pub fn deadline(&self, key: &[u8]) -> Option<u64> {
+        self.ttl.get(key)
+    }
+
+    /// Sets the deadline of an existing key, deleting it right away if the
+    /// deadline has passed. Returns `false` if the key does not exist.
+    pub fn expire_at(&mut self, key: &[u8], deadline: u64) -> bool {
+        self.purge(key);
+        if self.map.hm_lookup(key).is_none() {
+            return false;
+        }
+        if deadline <= now_ms() {
+            self.remove(key);
+        } else {
+            self.ttl.set(key, deadline);
+            self.touch(key);
+        }
+        true
+    }
+
+    /// Drops the deadline of `key`, returns `false` if it had none.
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        self.purge(key);
+        let persisted = self.ttl.remove(key).is_some();
+        if persisted {
+            self.touch(key);
+        }
+        persisted
+    }
+
+    /// Returns the earliest deadline of all volatile keys.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.ttl.peek().map(|(deadline, _)| deadline)
+    }
+
+    /// Deletes a key chosen by `policy` among `samples` random candidates,
+    /// the best of a few being close enough to the best of all. Returns
+    /// the key, or `None` if no key qualifies.
+    pub fn evict(&mut self, policy: EvictionPolicy, samples: usize) -> Option<Vec<u8>> {
+        let now = now_ms();
+        let secs = (now / 1000) as u32;
+        // the candidate with the highest score goes
+        let mut victim: Option<(u64, &[u8])> = None;
+        for _ in 0..samples {
+            let r = rand_u64();
+            let key = match policy {
+                EvictionPolicy::NoEviction => return None,
+                EvictionPolicy::AllKeysLru
+                | EvictionPolicy::AllKeysLfu
+                | EvictionPolicy::AllKeysRandom => self.map.hm_random(r)?.0.as_slice(),
+                EvictionPolicy::VolatileLru | EvictionPolicy::VolatileTtl => self.ttl.random(r)?,
+            };
+            let entry = self.map.hm_lookup(key)?;
+            let score = match policy {
+                EvictionPolicy::AllKeysLru | EvictionPolicy::VolatileLru => {
+                    secs.saturating_sub(entry.access.get()) as u64
+                }
+                EvictionPolicy::AllKeysLfu => (u8::MAX - entry.decayed_freq(secs)) as u64,
+                EvictionPolicy::VolatileTtl => u64::MAX - self.ttl.get(key).unwrap_or(now),
+                _ => 0,
+            };
+            if victim.is_none_or(|(best, _)| score > best) {
+                victim = Some((score, key));
+            }
+        }
+        let key = victim?.1.to_vec();
+        self.remove(&key);
+        self.evicted_keys += 1;
+        Some(key)
+    }
+
+    /// Returns how many keys `evict` has deleted.
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys
+    }
+
+    /// Returns how many keys have been deleted for expiring.
+    pub fn expired_keys(&self) -> u64 {
+        self.expired_keys
+    }
+
+    /// Returns the number of keys, expired ones not collected yet included.
+    pub fn len(&self) -> usize {
+        self.map.hm_size()
+    }
+
+    /// Returns the number of keys with a deadline.
+    pub fn expires(&self) -> usize {
+        self.ttl.len()
+    }
+
+    /// Deletes up to `max_work` keys whose deadline is at or before `now`,
+    /// returns how many were deleted.
+    pub fn expire_due(&mut self, now: u64, max_work: usize) -> usize {
+        let mut nwork = 0;
+        while nwork < max_work {
+            let key = match self.ttl.peek() {
+                Some((deadline, key)) if deadline <= now => key.to_vec(),
+                _ => break,
+            };
+            self.ttl.remove(&key);
+            self.map.hm_delete(&key);
+            self.expired_keys += 1;
+            self.touch(&key);
+            notify::notify(notify::EXPIRED, "expired", &key);
+            nwork += 1;
+        }
+        nwork
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+
+// the most elements kept in one chunk
+const K_CHUNK_MAX: usize = 128;
+
+/// A list of byte strings, stored as a deque of small chunks so pushes and
+/// pops at either end are O(1) and inserts in the middle only shift the
+/// elements of one chunk.
+pub struct List {
+    chunks: VecDeque<VecDeque<Vec<u8>>>,
+    len: usize,
+}
+
+impl List {
+    pub fn new() -> List {
+        List {
+            chunks: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push_front(&mut self, val: Vec<u8>) {
+        if self.chunks.front().is_none_or(|c| c.len() >= K_CHUNK_MAX) {
+            self.chunks.push_front(VecDeque::new());
+        }
+        self.chunks[0].push_front(val);
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, val: Vec<u8>) {
+        if self.chunks.back().is_none_or(|c| c.len() >= K_CHUNK_MAX) {
+            self.chunks.push_back(VecDeque::new());
+        }
+        self.chunks.back_mut().unwrap().push_back(val);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let chunk = self.chunks.front_mut()?;
+        let val = chunk.pop_front();
+        if chunk.is_empty() {
+            self.chunks.pop_front();
+        }
+        self.len -= 1;
+        val
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        let chunk = self.chunks.back_mut()?;
+        let val = chunk.pop_back();
+        if chunk.is_empty() {
+            self.chunks.pop_back();
+        }
+        self.len -= 1;
+        val
+    }
+
+    /// Finds the chunk holding element `i` and the offset in it, walking
+    /// from whichever end is closer.
+    fn locate(&self, i: usize) -> Option<(usize, usize)> {
+        if i >= self.len {
+            return None;
+        }
+        if i < self.len / 2 {
+            let mut off = i;
+            for (c, chunk) in self.chunks.iter().enumerate() {
+                if off < chunk.len() {
+                    return Some((c, off));
+                }
+                off -= chunk.len();
+            }
+        } else {
+            let mut off = self.len - 1 - i;
+            for (c, chunk) in self.chunks.iter().enumerate().rev() {
+                if off < chunk.len() {
+                    return Some((c, chunk.len() - 1 - off));
+                }
+                off -= chunk.len();
+            }
+        }
+        unreachable!("list length out of sync with its chunks")
+    }
+
+    pub fn get(&self, i: usize) -> Option<&[u8]> {
+        let (c, off) = self.locate(i)?;
+        Some(&self.chunks[c][off])
+    }
+
+    /// Replaces element `i`, returns `false` if it is out of range.
+    pub fn set(&mut self, i: usize, val: Vec<u8>) -> bool {
+        match self.locate(i) {
+            Some((c, off)) => {
+                self.chunks[c][off] = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Inserts `val` before element `i`, or at the end if `i` is the length.
+    pub fn insert(&mut self, i: usize, val: Vec<u8>) {
+        assert!(i <= self.len);
+        if i == self.len {
+            return self.push_back(val);
+        }
+        let (c, off) = self.locate(i).unwrap();
+        let chunk = &mut self.chunks[c];
+        chunk.insert(off, val);
+        if chunk.len() > K_CHUNK_MAX {
+            let tail = chunk.split_off(K_CHUNK_MAX / 2);
+            self.chunks.insert(c + 1, tail);
+        }
+        self.len += 1;
+    }
+
+    /// Returns the index of the first element equal to `val`.
+    pub fn position(&self, val: &[u8]) -> Option<usize> {
+        self.iter_from(0).position(|v| v == val)
+    }
+
+    /// Removes up to `count` elements equal to `val`, scanning from the tail
+    /// if `from_back` is set. Returns how many were removed.
+    pub fn remove_value(&mut self, val: &[u8], count: usize, from_back: bool) -> usize {
+        let mut removed = 0;
+        let order: Vec<usize> = if from_back {
+            (0..self.chunks.len()).rev().collect()
+        } else {
+            (0..self.chunks.len()).collect()
+        };
+        for c in order {
+            if removed == count {
+                break;
+            }
+            let chunk = &mut self.chunks[c];
+            let mut hits: Vec<usize> = (0..chunk.len()).filter(|&j| chunk[j] == val).collect();
+            if from_back {
+                hits.reverse();
+            }
+            hits.truncate(count - removed);
+            // remove from the highest offset down so the others stay valid
+            hits.sort_unstable_by(|a, b| b.cmp(a));
+            for j in &hits {
+                chunk.remove(*j);
+            }
+            removed += hits.len();
+        }
+        self.chunks.retain(|chunk| !chunk.is_empty());
+        self.len -= removed;
+        removed
+    }
+
+    /// Keeps only the elements from `start` to `stop` inclusive.
+    pub fn trim(&mut self, start: usize, stop: usize) {
+        let back = self.len - 1 - stop.min(self.len - 1);
+        self.drop_front(start);
+        self.drop_back(back);
+    }
+
+    fn drop_front(&mut self, mut n: usize) {
+        n = n.min(self.len);
+        self.len -= n;
+        while n > 0 {
+            let chunk = self.chunks.front_mut().unwrap();
+            if chunk.len() <= n {
+                n -= chunk.len();
+                self.chunks.pop_front();
+            } else {
+                chunk.drain(..n);
+                n = 0;
+            }
+        }
+    }
+
+    fn drop_back(&mut self, mut n: usize) {
+        n = n.min(self.len);
+        self.len -= n;
+        while n > 0 {
+            let chunk = self.chunks.back_mut().unwrap();
+            if chunk.len() <= n {
+                n -= chunk.len();
+                self.chunks.pop_back();
+            } else {
+                chunk.truncate(chunk.len() - n);
+                n = 0;
+            }
+        }
+    }
+
+    /// Iterates over the elements in order, starting at index `i`.
+    pub fn iter_from(&self, i: usize) -> impl Iterator<Item = &[u8]> {
+        let (c, off) = self.locate(i).unwrap_or((self.chunks.len(), 0));
+        self.chunks
+            .range(c..)
+            .enumerate()
+            .flat_map(move |(j, chunk)| chunk.range(if j == 0 { off } else { 0 }..))
+            .map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(list: &List, model: &VecDeque<Vec<u8>>) {
+        assert_eq!(list.len(), model.len());
+        assert!(list
+            .chunks
+            .iter()
+            .all(|c| !c.is_empty() && c.len() <= K_CHUNK_MAX));
+        assert_eq!(
+            list.chunks.iter().map(VecDeque::len).sum::<usize>(),
+            list.len()
+        );
+        assert!(list.iter_from(0).eq(model.iter().map(Vec::as_slice)));
+    }
+
+    fn val(n: u64) -> Vec<u8> {
+        (n % 7).to_string().into_bytes()
+    }
+
+    #[test]
+    fn matches_a_deque() {
+        let mut list = List::new();
+        let mut model = VecDeque::new();
+        let mut seed = 7u64;
+        for step in 0..20000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let r = seed >> 33;
+            match r % 10 {
+                0..=2 => {
+                    list.push_back(val(r));
+                    model.push_back(val(r));
+                }
+                3 | 4 => {
+                    list.push_front(val(r));
+                    model.push_front(val(r));
+                }
+                5 => assert_eq!(list.pop_front(), model.pop_front()),
+                6 => assert_eq!(list.pop_back(), model.pop_back()),
+                7 => {
+                    let i = (r / 10) as usize % (model.len() + 1);
+                    list.insert(i, val(r));
+                    model.insert(i, val(r));
+                }
+                8 if !model.is_empty() => {
+                    let i = (r / 10) as usize % model.len();
+                    assert!(list.set(i, val(r + 1)));
+                    model[i] = val(r + 1);
+                    assert_eq!(list.get(i), Some(model[i].as_slice()));
+                }
+                9 if step % 50 == 9 => {
+                    let (target, count) = (val(r), (r / 10) as usize % 4 + 1);
+                    let from_back = r % 20 < 10;
+                    let mut hits: Vec<usize> =
+                        (0..model.len()).filter(|&i| model[i] == target).collect();
+                    if from_back {
+                        hits.reverse();
+                    }
+                    hits.truncate(count);
+                    hits.sort_unstable_by(|a, b| b.cmp(a));
+                    for i in &hits {
+                        model.remove(*i);
+                    }
+                    assert_eq!(list.remove_value(&target, count, from_back), hits.len());
+                }
+                _ => {}
+            }
+            if step % 1000 == 0 {
+                check(&list, &model);
+            }
+        }
+        check(&list, &model);
+    }
+
+    #[test]
+    fn index_and_iterate() {
+        let mut list = List::new();
+        for n in 0..1000u64 {
+            list.push_back(n.to_string().into_bytes());
+        }
+        assert_eq!(list.get(0), Some(&b"0"[..]));
+        assert_eq!(list.get(999), Some(&b"999"[..]));
+        assert_eq!(list.get(1000), None);
+        assert_eq!(
+            list.iter_from(997).collect::<Vec<_>>(),
+            [b"997", b"998", b"999"]
+        );
+        assert_eq!(list.iter_from(1000).count(), 0);
+        assert_eq!(list.position(b"500"), Some(500));
+        assert_eq!(list.position(b"x"), None);
+        assert!(!list.set(1000, b"x".to_vec()));
+    }
+
+    #[test]
+    fn trim_across_chunks() {
+        let mut list = List::new();
+        let mut model = VecDeque::new();
+        for n in 0..1000u64 {
+            list.push_back(n.to_string().into_bytes());
+            model.push_back(n.to_string().into_bytes());
+        }
+        list.trim(130, 700);
+        model.drain(701..);
+        model.drain(..130);
+        check(&list, &model);
+        // a stop past the end keeps the tail
+        list.trim(1, 5000);
+        model.pop_front();
+        check(&list, &model);
+        list.trim(0, 0);
+        assert_eq!(list.iter_from(0).collect::<Vec<_>>(), [b"131"]);
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much the server prints, from everything to problems only.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    /// Every request as it is parsed.
+    Debug,
+    /// Connections coming and going.
+    Verbose,
+    /// Persistence and replication.
+    Notice,
+    /// Failures.
+    Warning,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "debug" => Some(Level::Debug),
+            "verbose" => Some(Level::Verbose),
+            "notice" => Some(Level::Notice),
+            "warning" => Some(Level::Warning),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Verbose => "verbose",
+            Level::Notice => "notice",
+            Level::Warning => "warning",
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Notice as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns `true` if messages of `level` are printed.
+pub fn enabled(level: Level) -> bool {
+    level as u8 >= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Prints a message if its level is enabled, e.g.
+/// `log!(Notice, "loaded {path}")`.
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::$level) {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use log;
@@ -1,428 +1,3843 @@
-use hashtable::{HMap, HNode, Link};
+use acl::Acl;
+use aof::Aof;
+use blocking::Waiters;
+use config::Config;
+use hashtable::HMap;
+use keyspace::{now_ms, Keyspace};
 use lazy_static::lazy_static;
-use mio::net::{TcpListener, TcpStream};
+use list::List;
+use log::log;
+use mio::net::TcpStream;
 use mio::{Events, Interest, Poll, Token};
-use std::cell::RefCell;
-use std::collections::HashMap;
+use net::{Listener, Stream};
+use pubsub::{Delivery, PubSub};
+use replication::Replication;
+use set::Set;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Error, ErrorKind, Read, Write};
-use std::rc::Rc;
-use std::sync::Mutex;
-use std::time::Duration;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use zset::ZSet;
+mod acl;
+mod aof;
+mod avl;
+mod blocking;
+mod config;
+mod glob;
 mod hashtable;
-const SERVER: Token = Token(0);
-const K_MAX_MSG: usize = 4096;
-const K_MAX_ARGS: usize = 1024;
+mod heap;
+mod info;
+mod keyspace;
+mod list;
+mod log;
+mod memory;
+mod net;
+mod notify;
+mod pubsub;
+mod replication;
+mod resp;
+mod set;
+mod sha256;
+mod snapshot;
+mod zset;
+#[global_allocator]
+static GLOBAL: memory::Counting = memory::Counting;
+const K_READ_CHUNK: usize = 64 << 10;
+// the size of the `snapchunk` records a snapshot is sent to a replica in
+const K_SNAPSHOT_CHUNK: usize = 256 << 10;
+// the most keys collected by a single `process_timers` call
+const K_MAX_EXPIRE_WORK: usize = 2000;
+
+/// The wire protocol of a connection, detected from its first request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Proto {
+    Unknown,
+    // the length-prefixed framing of client.rs
+    Binary,
+    Resp2,
+    // switched to with `HELLO 3`
+    Resp3,
+}
 
 #[derive(Debug, PartialEq)]
 enum State {
     Reading,
     Writing,
+    // waiting in BLPOP and the like; requests that arrive in the meantime
+    // are buffered but not handled
+    Blocked,
     Closed,
 }
 
+/// What a blocked connection is waiting for.
+struct Blocked {
+    keys: Vec<Vec<u8>>,
+    deadline: Option<Instant>,
+    // pop from the head of the list
+    front: bool,
+    // for BLMOVE, the list to push to and whether to push at its head
+    dest: Option<(Vec<u8>, bool)>,
+}
+
+/// A full resync a replica is receiving from its primary.
+struct Resync {
+    replid: String,
+    // the offset in the history of the primary the snapshot is at
+    offset: u64,
+    size: usize,
+    // the snapshot received so far
+    data: Vec<u8>,
+}
+
+/// The value stored under a key.
+enum Value {
+    Str(Vec<u8>),
+    ZSet(ZSet),
+    Hash(HMap<Vec<u8>, Vec<u8>>),
+    List(List),
+    Set(Set),
+}
+
+/// How SINTER, SUNION and SDIFF combine their sets.
+#[derive(Clone, Copy)]
+enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// Combines the sets stored at `keys`, a missing key being an empty set.
+/// Returns `None` if a key holds another type.
+fn combine_sets(map: &Keyspace, keys: &[Vec<u8>], op: SetOp) -> Option<Set> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        match map.get(key) {
+            None => sets.push(None),
+            Some(Value::Set(set)) => sets.push(Some(set)),
+            Some(_) => return None,
+        }
+    }
+
+    let mut result = Set::new();
+    match op {
+        SetOp::Union => {
+            for set in sets.iter().flatten() {
+                for member in set.members() {
+                    result.add(&member);
+                }
+            }
+        }
+        SetOp::Inter => {
+            if sets.iter().any(Option::is_none) {
+                return Some(result);
+            }
+            let mut sets: Vec<&Set> = sets.into_iter().flatten().collect();
+            // walk the smallest set, probe the others
+            sets.sort_by_key(|set| set.len());
+            for member in sets[0].members() {
+                if sets[1..].iter().all(|set| set.contains(&member)) {
+                    result.add(&member);
+                }
+            }
+        }
+        SetOp::Diff => {
+            if let Some(first) = sets[0] {
+                for member in first.members() {
+                    if !sets[1..].iter().flatten().any(|set| set.contains(&member)) {
+                        result.add(&member);
+                    }
+                }
+            }
+        }
+    }
+    Some(result)
+}
+
 lazy_static! {
-    static ref G_MAP: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    static ref G_CONFIG: RwLock<Config> = RwLock::new(Config::new());
+    static ref G_MAP: Mutex<Keyspace> = Mutex::new(Keyspace::new());
+    static ref G_AOF: Mutex<Option<Aof>> = Mutex::new(None);
+    static ref G_PUBSUB: Mutex<PubSub> = Mutex::new(PubSub::new());
+    static ref G_REPL: Mutex<Replication> = Mutex::new(Replication::new());
+    static ref G_ACL: Mutex<Acl> = Mutex::new(Acl::new());
+    // the writes of the transaction being run by EXEC
+    static ref G_TX: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+}
+fn cmd_is(word: &[u8], cmd: &str) -> bool {
+    word.eq_ignore_ascii_case(cmd.as_bytes())
+}
+
+fn str2dbl(s: &[u8]) -> Option<f64> {
+    std::str::from_utf8(s)
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|v| !v.is_nan())
+}
+
+fn str2int(s: &[u8]) -> Option<i64> {
+    std::str::from_utf8(s).ok()?.parse::<i64>().ok()
+}
+
+/// Parses a request body, `nstr | (len | str)*`, into its arguments.
+fn parse_req(data: &[u8], cmd: &mut Vec<Vec<u8>>) -> std::io::Result<()> {
+    if data.len() < 8 {
+        return Err(Error::other("Bad request!"));
+    }
+
+    // Extract the number of strings from the first 4 bytes
+    let mut n = u32::from_le_bytes(data[0..4].try_into().unwrap());
+
+    log!(Debug, "{} number of strings", n);
+    if n as usize > G_CONFIG.read().unwrap().max_args {
+        return Err(Error::other("n > max-args"));
+    }
+
+    let mut pos = 4_usize; // Start reading strings from position 4
+    while n > 0 {
+        n -= 1;
+        // Check if there are enough bytes to read the length of the string
+        if pos + 4 > data.len() {
+            return Err(Error::other("string not expected"));
+        }
+
+        let sz = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        log!(Debug, "sz is {sz}");
+
+        // Check if there are enough bytes to read the string content
+        if pos + 4 + sz > data.len() {
+            return Err(Error::other("too less information"));
+        }
+
+        // arguments are arbitrary bytes, not necessarily UTF-8
+        cmd.push(data[pos + 4..pos + 4 + sz].to_vec());
+        log!(Debug, "the command is {:?}", cmd);
+
+        pos += 4 + sz;
+    }
+
+    if pos != data.len() {
+        return Err(Error::new(io::ErrorKind::InvalidData, "Garbage trailing!"));
+    }
+
+    Ok(())
+}
+
+/// Frames `cmd` as a request, the inverse of `parse_req`.
+fn encode_req(cmd: &[&[u8]]) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    body.extend((cmd.len() as u32).to_le_bytes());
+    for s in cmd {
+        body.extend((s.len() as u32).to_le_bytes());
+        body.extend(*s);
+    }
+    let mut req = Vec::with_capacity(4 + body.len());
+    req.extend((body.len() as u32).to_le_bytes());
+    req.extend(body);
+    req
+}
+
+/// Records a command that modified the keyspace in the append-only file
+/// and sends it to the replicas. Commands must be logged in a form that
+/// replays to the same state, e.g. relative expirations are logged as
+/// `pexpireat`.
+fn propagate(cmd: &[&[u8]]) {
+    let req = encode_req(cmd);
+    if let Some(tx) = G_TX.lock().unwrap().as_mut() {
+        tx.extend(req);
+        return;
+    }
+    write_log(&req);
+}
+
+fn write_log(req: &[u8]) {
+    if let Some(aof) = G_AOF.lock().unwrap().as_mut() {
+        if let Err(e) = aof.append(req) {
+            log!(Warning, "aof write failed: {e}");
+        }
+    }
+    G_REPL.lock().unwrap().feed(req);
+}
+
+/// Returns the memory in use.
+fn used_memory() -> usize {
+    memory::used()
+}
+
+/// Returns the memory in use that evicting keys cannot free: the buffers
+/// of the clients and replicas and the replication backlog. As in redis,
+/// it does not count against `maxmemory`, or a slow replica or a large
+/// reply would have every key evicted.
+fn not_counted_memory() -> usize {
+    memory::buffers() + G_REPL.lock().unwrap().backlog_memory()
 }
-fn cmd_is(word: &String, cmd: &str) -> bool {
-    word.eq_ignore_ascii_case(cmd)
+
+/// Evicts keys until the memory in use is within `maxmemory`. Returns
+/// `false` if it is still over the limit.
+fn evict_keys() -> bool {
+    let (limit, policy, samples) = {
+        let config = G_CONFIG.read().unwrap();
+        (
+            config.maxmemory,
+            config.maxmemory_policy,
+            config.maxmemory_samples,
+        )
+    };
+    // a replica keeps whatever its primary has
+    if limit == 0 || G_REPL.lock().unwrap().is_replica() {
+        return true;
+    }
+    let mut map = G_MAP.lock().unwrap();
+    while used_memory().saturating_sub(not_counted_memory()) > limit {
+        let Some(key) = map.evict(policy, samples) else {
+            return false;
+        };
+        propagate(&[b"del", &key]);
+        notify::notify(notify::EVICTED, "evicted", &key);
+    }
+    true
+}
+
+/// Holds back the commands logged from now on, the writes of a
+/// transaction, until `propagate_commit`.
+fn propagate_begin() {
+    *G_TX.lock().unwrap() = Some(Vec::new());
+}
+
+/// Logs the writes of a transaction wrapped in MULTI and EXEC, so that a
+/// log cut short in the middle of them replays none of them.
+fn propagate_commit() {
+    let Some(body) = G_TX.lock().unwrap().take() else {
+        return;
+    };
+    if body.is_empty() {
+        return;
+    }
+    let mut req = encode_req(&[b"multi"]);
+    req.extend(body);
+    req.extend(encode_req(&[b"exec"]));
+    write_log(&req);
+}
+
+/// Parses a ZRANGEBYSCORE bound such as `1.5`, `(1.5` or `-inf`, returning
+/// the score and whether the bound is exclusive.
+fn str2bound(s: &[u8]) -> Option<(f64, bool)> {
+    match s.strip_prefix(b"(") {
+        Some(rest) => str2dbl(rest).map(|v| (v, true)),
+        None => str2dbl(s).map(|v| (v, false)),
+    }
+}
+
+/// Resolves an inclusive `start..stop` index range over `len` elements,
+/// where negative indexes count from the end. Returns `None` if the range
+/// is empty.
+fn index_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        stop + len
+    } else {
+        stop.min(len - 1)
+    };
+    (start <= stop).then_some((start as usize, stop as usize))
+}
+
+/// Resolves a single index, negative ones count from the end.
+fn list_index(i: i64, len: usize) -> Option<usize> {
+    let i = if i < 0 { i + len as i64 } else { i };
+    (0..len as i64).contains(&i).then_some(i as usize)
+}
+
+// the commands that may need more memory, refused when over `maxmemory`
+const DENYOOM_CMDS: [&str; 19] = [
+    "set",
+    "incr",
+    "decr",
+    "incrby",
+    "decrby",
+    "incrbyfloat",
+    "zadd",
+    "hset",
+    "hincrby",
+    "lpush",
+    "rpush",
+    "lset",
+    "linsert",
+    "lmove",
+    "blmove",
+    "sadd",
+    "sinterstore",
+    "sunionstore",
+    "sdiffstore",
+];
+
+// the commands that modify the keyspace, refused by a replica
+const WRITE_CMDS: [&str; 34] = [
+    "set",
+    "del",
+    "incr",
+    "decr",
+    "incrby",
+    "decrby",
+    "incrbyfloat",
+    "expire",
+    "pexpire",
+    "pexpireat",
+    "persist",
+    "zadd",
+    "zrem",
+    "hset",
+    "hdel",
+    "hincrby",
+    "lpush",
+    "rpush",
+    "lpop",
+    "rpop",
+    "lset",
+    "linsert",
+    "lrem",
+    "ltrim",
+    "blpop",
+    "brpop",
+    "lmove",
+    "blmove",
+    "sadd",
+    "srem",
+    "spop",
+    "sinterstore",
+    "sunionstore",
+    "sdiffstore",
+];
+
+// the commands of the admin category, besides ACL SETUSER and the rest
+const ADMIN_CMDS: [&str; 9] = [
+    "info",
+    "save",
+    "bgsave",
+    "config",
+    "memory",
+    "replicaof",
+    "psync",
+    "role",
+    "acl",
+];
+
+const PUBSUB_CMDS: [&str; 6] = [
+    "subscribe",
+    "psubscribe",
+    "unsubscribe",
+    "punsubscribe",
+    "publish",
+    "pubsub",
+];
+
+// the commands any authenticated user may run
+const CONNECTION_CMDS: [&str; 8] = [
+    "ping", "echo", "hello", "auth", "multi", "exec", "discard", "unwatch",
+];
+
+// the commands that take no key, every other one has one at `cmd[1]` or
+// more, see `command_keys`
+const KEYLESS_CMDS: [&str; 27] = [
+    "info",
+    "ping",
+    "echo",
+    "hello",
+    "auth",
+    "acl",
+    "keys",
+    "save",
+    "bgsave",
+    "config",
+    "memory",
+    "replicaof",
+    "psync",
+    "fullresync",
+    "snapchunk",
+    "continue",
+    "role",
+    "subscribe",
+    "psubscribe",
+    "unsubscribe",
+    "punsubscribe",
+    "publish",
+    "pubsub",
+    "multi",
+    "exec",
+    "discard",
+    "unwatch",
+];
+
+/// Returns the ACL category of a command, 0 for the connection commands
+/// everyone may run.
+fn command_category(cmd: &[Vec<u8>]) -> u32 {
+    let is_one_of = |names: &[&str]| names.iter().any(|name| cmd_is(&cmd[0], name));
+    let whoami = cmd.len() == 2 && cmd_is(&cmd[0], "acl") && cmd_is(&cmd[1], "whoami");
+    if is_one_of(&CONNECTION_CMDS) || whoami {
+        0
+    } else if is_one_of(&PUBSUB_CMDS) {
+        acl::PUBSUB
+    } else if is_one_of(&ADMIN_CMDS) {
+        acl::ADMIN
+    } else if is_one_of(&WRITE_CMDS) {
+        acl::WRITE
+    } else {
+        acl::READ
+    }
+}
+
+/// Returns the user new clients start out as: `default` unless it needs a
+/// password.
+fn default_user() -> Option<String> {
+    let acl = G_ACL.lock().unwrap();
+    let open = acl.user("default").is_some_and(acl::User::is_open);
+    open.then(|| "default".to_string())
+}
+
+/// Returns the keys a command accesses.
+fn command_keys(cmd: &[Vec<u8>]) -> &[Vec<u8>] {
+    let is_one_of = |names: &[&str]| names.iter().any(|name| cmd_is(&cmd[0], name));
+    if cmd.len() < 2 || is_one_of(&KEYLESS_CMDS) {
+        &[]
+    } else if is_one_of(&["blpop", "brpop"]) {
+        // the last argument is the timeout
+        &cmd[1..cmd.len() - 1]
+    } else if is_one_of(&["lmove", "blmove"]) {
+        &cmd[1..cmd.len().min(3)]
+    } else if is_one_of(&[
+        "sinter",
+        "sunion",
+        "sdiff",
+        "sinterstore",
+        "sunionstore",
+        "sdiffstore",
+    ]) || is_one_of(&["watch"])
+    {
+        &cmd[1..]
+    } else {
+        &cmd[1..2]
+    }
 }
 
+/// Runs a request on a connection, writing the reply to `out`.
+type Handler = fn(&mut Conn, &[Vec<u8>], &mut Vec<u8>);
+
 struct Conn {
+    // the token of the connection in the event loop
+    token: Token,
     state: State,
-    stream: TcpStream,
-    rbuf_size: usize,
-    rbuf: [u8; 4 + K_MAX_MSG],
-    wbuf_size: usize,
-    wbuf: [u8; 4 + K_MAX_MSG],
+    // `None` for the pseudo connection that replays the append-only file
+    stream: Option<Stream>,
+    proto: Proto,
+    rbuf: Vec<u8>,
+    // where the next unhandled request in `rbuf` starts
+    rbuf_pos: usize,
+    wbuf: Vec<u8>,
     wbuf_sent: usize,
+    // when the pending output went over `output-soft-limit`
+    soft_limit_since: Option<Instant>,
+    blocked: Option<Blocked>,
+    // the pub/sub subscriptions of the connection
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    // the commands queued since MULTI
+    multi: Option<Vec<Vec<Vec<u8>>>>,
+    // a command could not be queued, EXEC will refuse to run the rest
+    multi_failed: bool,
+    // running the commands of EXEC, which must not block
+    in_exec: bool,
+    // the keys of WATCH
+    watched: Vec<Vec<u8>>,
+    // the connection of a replica to its primary, whose requests are the
+    // replication stream and get no replies
+    link: bool,
+    // on a link, the size of the records of a transaction not run yet
+    link_pending: u64,
+    // on a link, waiting for the reply to the AUTH sent ahead of PSYNC
+    link_auth: bool,
+    // for a replica of ours, the backlog offset it has been sent up to
+    replica_offset: Option<u64>,
+    // for a replica of ours, the snapshot of a full resync and how much of
+    // it has been sent; the stream follows once it has been sent whole
+    snapshot_out: Option<(Vec<u8>, usize)>,
+    // on a link, the full resync being received
+    resync: Option<Resync>,
+    // the user the client authenticated as, `None` until it does
+    user: Option<String>,
+    // the size of the buffers as last counted in `memory::buffers`
+    buffers_size: usize,
 }
 
 impl Conn {
     fn state_req(&mut self) {
         while self.try_fill_buffer() {}
+        // flush the replies to everything read so far together
+        if self.state != State::Closed && self.wbuf_sent < self.wbuf.len() {
+            if self.state == State::Reading {
+                self.state = State::Writing;
+            }
+            self.state_res();
+        }
     }
 
     fn try_fill_buffer(&mut self) -> bool {
-        assert!(self.rbuf_size < self.rbuf.len());
         match self.read() {
             Ok(n) => {
-                println!(
+                log!(
+                    Debug,
                     "I just read {n} bytes and am filled with {} bytes of data",
-                    self.rbuf_size
+                    self.rbuf.len()
                 );
 
+                self.count_buffers();
                 if n == 0 {
-                    if self.rbuf_size > 0 {
-                        println!("unexpected EOF");
+                    if !self.rbuf.is_empty() {
+                        log!(Verbose, "unexpected EOF");
                     } else {
-                        println!("EOF");
+                        log!(Verbose, "EOF");
                     }
                     self.state = State::Closed;
                     return false;
                 }
-                self.rbuf_size += n;
-                assert!(self.rbuf_size <= self.rbuf.len());
 
+                // answer every complete request, then drop them all at once
                 while self.try_one_request() {}
-                return self.state == State::Reading;
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                return false;
-            }
-            Err(ref e) if e.kind() == ErrorKind::Interrupted => {
-                return true;
+                self.rbuf.drain(..self.rbuf_pos);
+                self.rbuf_pos = 0;
+
+                if self.rbuf.len() > G_CONFIG.read().unwrap().max_query_buf {
+                    log!(Warning, "query buffer over the limit, closing");
+                    self.state = State::Closed;
+                }
+                self.state != State::Closed
             }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => true,
             Err(_) => {
                 self.state = State::Closed;
-                return false;
+                false
+            }
+        }
+    }
+
+    /// Parses the request at `rbuf_pos`, returning its arguments and length,
+    /// or `None` if it hasn't been received completely.
+    fn parse_one(&mut self) -> std::io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+        let data = &self.rbuf[self.rbuf_pos..];
+        let (max_args, max_msg) = {
+            let config = G_CONFIG.read().unwrap();
+            (config.max_args, config.max_msg)
+        };
+        match self.proto {
+            Proto::Unknown => Ok(None),
+            Proto::Resp2 | Proto::Resp3 => resp::parse_request(data, max_args, max_msg),
+            Proto::Binary => {
+                if data.len() < 4 {
+                    return Ok(None);
+                }
+                let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+                log!(Debug, "{} is the length of the message", len);
+                if len > max_msg {
+                    return Err(Error::other(format!("len too big: {len}")));
+                }
+                // not enough data in buffer retry again
+                if 4 + len > data.len() {
+                    return Ok(None);
+                }
+                let mut cmd: Vec<Vec<u8>> = vec![];
+                parse_req(&data[4..4 + len], &mut cmd)?;
+                Ok(Some((cmd, 4 + len)))
             }
         }
     }
 
+    /// Handles the next request in `rbuf` and queues its reply, returns
+    /// `false` once no complete request is left.
     fn try_one_request(&mut self) -> bool {
-        if self.rbuf_size < 4 {
+        if self.state == State::Blocked {
             return false;
         }
+        if self.link_auth {
+            return self.read_auth_reply();
+        }
+        if self.proto == Proto::Unknown {
+            // the first request tells which protocol the client speaks
+            match resp::detect(&self.rbuf[self.rbuf_pos..]) {
+                None => return false,
+                Some(true) => self.proto = Proto::Resp2,
+                Some(false) => self.proto = Proto::Binary,
+            }
+        }
 
-        let buf = &self.rbuf[..4];
-        let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
-        println!("{} is the length of the message", len);
-        if len > K_MAX_MSG {
-            println!("len too big: {}", len);
+        let (cmd, reqlen) = match self.parse_one() {
+            Ok(Some(req)) => req,
+            Ok(None) => return false,
+            Err(e) => {
+                log!(Verbose, "{:?}", e);
+                self.state = State::Closed;
+                return false;
+            }
+        };
+        self.rbuf_pos += reqlen;
+        let mut out: Vec<u8> = Vec::new();
+        log!(Debug, "Successfully parsed!");
+        if self.do_request(&cmd, &mut out).is_err() {
             self.state = State::Closed;
             return false;
         }
-        // not enough data in buffer retry again
-
-        if 4 + len > self.rbuf_size {
+        if self.link {
+            // the replication offset counts the stream applied so far, a
+            // transaction once it has run; the sync records are not part
+            // of the stream
+            let sync = ["fullresync", "snapchunk", "continue"];
+            if !sync.iter().any(|name| cmd_is(&cmd[0], name)) {
+                self.link_pending += reqlen as u64;
+            }
+            if self.multi.is_none() {
+                G_REPL.lock().unwrap().primary_offset += self.link_pending;
+                self.link_pending = 0;
+            }
+            return self.state != State::Closed;
+        }
+        // the reply of a blocking command is queued once it is served
+        if self.state == State::Blocked {
             return false;
         }
-        // got one request, generate the response
-        println!("The client says {:?}", &self.rbuf[4..len + 4]);
-
 
-        let mut cmd :Vec<String>=vec![];
-        match self.parse_req(len, &mut cmd) {
-            std::io::Result::Ok(_) => {}
-            Err(E) => {
-                println!("{:?}", E);
-                self.state=State::Closed;
-                return false;
-            }
+        // commands that reply with several frames have queued them already
+        if !out.is_empty() {
+            self.queue_reply(&out);
         }
-        let mut out:Vec<u8>=Vec::new();
-        println!("Successfully parsed!");
-        match self.do_request(&mut cmd,&mut out) {
-            std::io::Result::Ok(_) => {}
+        self.state != State::Closed
+    }
 
-            Err(_) => {
-                self.state = State::Closed;
-                return false;
-            }
+    /// Reads the primary's reply to the AUTH of a link, the one reply a link
+    /// gets; a replica refused is logged and retries later.
+    fn read_auth_reply(&mut self) -> bool {
+        let data = &self.rbuf[self.rbuf_pos..];
+        if data.len() < 4 {
+            return false;
+        }
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        let Some(reply) = data.get(4..4 + len) else {
+            return false;
+        };
+        if reply.first() != Some(&(Serialization::SER_NIL as u8)) {
+            // an error: the tag, the code and the length of the message
+            let msg = String::from_utf8_lossy(reply.get(9..).unwrap_or_default());
+            log!(Warning, "authenticating to the primary failed: {msg}");
+            self.state = State::Closed;
+            return false;
         }
+        self.rbuf_pos += 4 + len;
+        self.link_auth = false;
+        true
+    }
 
-        if 4+out.len()>K_MAX_MSG{
-            out.clear();
-            self.out_err(&mut out, ErrorCode::RES_NX, &String::from("Response is too big"));
+    /// Appends a reply to the output buffer, framing it for the binary
+    /// protocol, and enforces the output buffer limits.
+    fn queue_reply(&mut self, out: &[u8]) {
+        if self.proto == Proto::Binary {
+            self.wbuf.extend((out.len() as u32).to_le_bytes());
         }
+        self.wbuf.extend(out);
+        self.check_output_limits();
+    }
 
+    /// Queues replication stream bytes, framed already, and sends them.
+    fn send_stream(&mut self, data: &[u8]) {
+        self.wbuf.extend(data);
+        self.check_output_limits();
+        if self.state == State::Reading {
+            self.state = State::Writing;
+        }
+        if self.state != State::Closed {
+            self.state_res();
+        }
+    }
 
+    fn check_output_limits(&mut self) {
+        self.count_buffers();
+        let pending = self.wbuf.len() - self.wbuf_sent;
+        let config = G_CONFIG.read().unwrap();
+        if pending <= config.output_soft_limit {
+            self.soft_limit_since = None;
+            return;
+        }
+        let since = *self.soft_limit_since.get_or_insert_with(Instant::now);
+        if pending > config.output_hard_limit
+            || since.elapsed() > Duration::from_secs(config.output_soft_secs)
+        {
+            log!(
+                Warning,
+                "output buffer over the limit ({pending} bytes), closing"
+            );
+            self.state = State::Closed;
+        }
+    }
 
-        let wlen=out.len();
-        self.wbuf[0..4].copy_from_slice(&(wlen as u32).to_le_bytes());
-        self.wbuf[4..4+out.len()].copy_from_slice(&out);
+    /// Updates the memory counted as buffers with the size of ours, which
+    /// grow on demand.
+    fn count_buffers(&mut self) {
+        let snapshot = self.snapshot_out.as_ref().map_or(0, |(s, _)| s.capacity());
+        let size = self.rbuf.capacity() + self.wbuf.capacity() + snapshot;
+        memory::resize_buffers(self.buffers_size, size);
+        self.buffers_size = size;
+    }
 
-        self.wbuf_size = 4 + wlen;
+    fn do_request(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) -> std::io::Result<()> {
+        // the parsers let no empty request through, but the checks below
+        // all look at the command
+        if cmd.is_empty() {
+            self.out_err(out, ErrorCode::RES_ERR, "Unknown CMD");
+            return Ok(());
+        }
 
-        println!("{:?}", &self.wbuf[..self.wbuf_size]);
+        // the append-only file and the primary are trusted, clients may
+        // only run what their user allows
+        if self.stream.is_some() && !self.link {
+            if let Err((err, msg)) = self.check_acl(cmd) {
+                if self.multi.is_some() {
+                    self.multi_failed = true;
+                }
+                self.out_err(out, err, &msg);
+                return Ok(());
+            }
+        }
 
-        //removing the request from the buffer
+        // without RESP3 pushes, a subscriber's replies would be mixed up
+        // with its messages
+        if self.is_subscriber() && self.proto != Proto::Resp3 {
+            let allowed = [
+                "subscribe",
+                "unsubscribe",
+                "psubscribe",
+                "punsubscribe",
+                "ping",
+            ];
+            if !allowed.iter().any(|name| cmd_is(&cmd[0], name)) {
+                let msg = format!(
+                    "Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context",
+                    String::from_utf8_lossy(&cmd[0]).to_lowercase()
+                );
+                self.out_err(out, ErrorCode::RES_ERR, &msg);
+                return Ok(());
+            }
+        }
 
-        let remaining = self.rbuf_size - 4 - len;
+        // a replica takes writes from its primary only
+        if !self.link
+            && G_REPL.lock().unwrap().is_replica()
+            && WRITE_CMDS.iter().any(|name| cmd_is(&cmd[0], name))
+        {
+            if self.multi.is_some() {
+                self.multi_failed = true;
+            }
+            let msg = "You can't write against a read only replica.";
+            self.out_err(out, ErrorCode::RES_READONLY, msg);
+            return Ok(());
+        }
 
-        if remaining > 0 {
-            self.rbuf[..].copy_within((4 + len)..(4 + len + remaining), 0);
+        // keys are evicted before running a command, if that does not
+        // free enough only commands that take no more memory may run; the
+        // replayed append-only file is loaded whatever it takes
+        if self.stream.is_some()
+            && !evict_keys()
+            && DENYOOM_CMDS.iter().any(|name| cmd_is(&cmd[0], name))
+        {
+            if self.multi.is_some() {
+                self.multi_failed = true;
+            }
+            let msg = "command not allowed when used memory > 'maxmemory'.";
+            self.out_err(out, ErrorCode::RES_OOM, msg);
+            return Ok(());
         }
-        self.rbuf_size = remaining;
 
-        self.state = State::Writing;
+        // between MULTI and EXEC commands are queued instead of run
+        if self.multi.is_some() {
+            let control = ["multi", "exec", "discard", "watch"];
+            if !control.iter().any(|name| cmd_is(&cmd[0], name)) {
+                self.queue_command(cmd, out);
+                return Ok(());
+            }
+        }
 
-        self.state_res();
+        match Self::route(cmd) {
+            Some(handler) => {
+                let start = Instant::now();
+                handler(self, cmd, out);
+                // the replayed append-only file does not count
+                if self.stream.is_some() {
+                    let name = String::from_utf8_lossy(&cmd[0]);
+                    info::record_command(&name, start.elapsed().as_micros() as u64);
+                }
+            }
+            None => self.out_err(out, ErrorCode::RES_ERR, "Unknown CMD"),
+        }
+        std::io::Result::Ok(())
+    }
 
-        return self.state == State::Reading;
+    /// Checks that the client is authenticated and that its user may run
+    /// `cmd` on the keys it accesses.
+    fn check_acl(&mut self, cmd: &[Vec<u8>]) -> Result<(), (ErrorCode, String)> {
+        let acl = G_ACL.lock().unwrap();
+        // a user removed or turned off since has to authenticate again
+        let user = self.user.as_deref().and_then(|name| acl.user(name));
+        let Some(user) = user.filter(|user| user.enabled) else {
+            self.user = None;
+            if cmd_is(&cmd[0], "auth") || (cmd_is(&cmd[0], "hello") && cmd.len() == 5) {
+                return Ok(());
+            }
+            return Err((
+                ErrorCode::RES_NOAUTH,
+                "Authentication required.".to_string(),
+            ));
+        };
+        if !user.can_run(command_category(cmd)) {
+            let msg = format!(
+                "User {} has no permissions to run the '{}' command",
+                self.user.as_deref().unwrap(),
+                String::from_utf8_lossy(&cmd[0]).to_lowercase()
+            );
+            return Err((ErrorCode::RES_NOPERM, msg));
+        }
+        if !command_keys(cmd).iter().all(|key| user.can_access(key)) {
+            let msg = "No permissions to access a key".to_string();
+            return Err((ErrorCode::RES_NOPERM, msg));
+        }
+        Ok(())
     }
 
-    fn do_request(
-        &mut self,
-        cmd: &mut Vec<String>,
-        out: &mut Vec<u8>,
-    ) -> std::io::Result<()> {
-        
-        if(cmd.len()==1 && cmd_is(&cmd[0],"keys" )){
-           self.do_keys(cmd,out);
-        }
-        else if cmd.len() == 2 && cmd_is(&cmd[0], "get") {
-            self.do_get(cmd, out);
-        } else if cmd.len() == 3 && cmd_is(&cmd[0], "set") {
-             self.do_set(cmd, out);
+    /// Looks up the handler of `cmd` by its name and number of arguments.
+    fn route(cmd: &[Vec<u8>]) -> Option<Handler> {
+        let handler: Handler = if cmd.len() <= 2 && cmd_is(&cmd[0], "ping") {
+            |c, cmd, out| c.do_ping(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "echo") {
+            |c, cmd, out| c.out_str(out, &cmd[1])
+        } else if (cmd.len() == 2 || cmd.len() == 3) && cmd_is(&cmd[0], "auth") {
+            |c, cmd, out| c.do_auth(cmd, out)
+        } else if cmd_is(&cmd[0], "hello") {
+            |c, cmd, out| c.do_hello(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "keys") {
+            |c, cmd, out| c.do_keys(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "get") {
+            |c, cmd, out| c.do_get(cmd, out)
+        } else if (cmd.len() == 3 || cmd.len() == 5) && cmd_is(&cmd[0], "set") {
+            |c, cmd, out| c.do_set(cmd, out)
         } else if cmd.len() == 2 && cmd_is(&cmd[0], "del") {
-            self.do_del(cmd, out);
+            |c, cmd, out| c.do_del(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "incr") {
+            |c, cmd, out| c.do_incr(cmd, out, 1)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "decr") {
+            |c, cmd, out| c.do_incr(cmd, out, -1)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "incrby") {
+            |c, cmd, out| c.do_incr(cmd, out, 1)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "decrby") {
+            |c, cmd, out| c.do_incr(cmd, out, -1)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "incrbyfloat") {
+            |c, cmd, out| c.do_incrbyfloat(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "expire") {
+            |c, cmd, out| c.do_expire(cmd, out, 1000)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "pexpire") {
+            |c, cmd, out| c.do_expire(cmd, out, 1)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "ttl") {
+            |c, cmd, out| c.do_ttl(cmd, out, 1000)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "pttl") {
+            |c, cmd, out| c.do_ttl(cmd, out, 1)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "pexpireat") {
+            |c, cmd, out| c.do_pexpireat(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "persist") {
+            |c, cmd, out| c.do_persist(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "save") {
+            |c, cmd, out| c.do_save(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "bgsave") {
+            |c, cmd, out| c.do_bgsave(cmd, out)
+        } else if cmd.len() >= 4 && cmd_is(&cmd[0], "zadd") {
+            |c, cmd, out| c.do_zadd(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "zrem") {
+            |c, cmd, out| c.do_zrem(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "zscore") {
+            |c, cmd, out| c.do_zscore(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "zrank") {
+            |c, cmd, out| c.do_zrank(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "zcard") {
+            |c, cmd, out| c.do_zcard(cmd, out)
+        } else if cmd.len() >= 4 && cmd_is(&cmd[0], "zrange") {
+            |c, cmd, out| c.do_zrange(cmd, out)
+        } else if cmd.len() >= 4 && cmd_is(&cmd[0], "zrangebyscore") {
+            |c, cmd, out| c.do_zrangebyscore(cmd, out)
+        } else if cmd.len() >= 4 && cmd_is(&cmd[0], "hset") {
+            |c, cmd, out| c.do_hset(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "hget") {
+            |c, cmd, out| c.do_hget(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "hmget") {
+            |c, cmd, out| c.do_hmget(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "hdel") {
+            |c, cmd, out| c.do_hdel(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "hexists") {
+            |c, cmd, out| c.do_hexists(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "hlen") {
+            |c, cmd, out| c.do_hlen(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "hkeys") {
+            |c, cmd, out| c.do_hgetall(cmd, out, true, false)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "hvals") {
+            |c, cmd, out| c.do_hgetall(cmd, out, false, true)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "hgetall") {
+            |c, cmd, out| c.do_hgetall(cmd, out, true, true)
+        } else if cmd.len() == 4 && cmd_is(&cmd[0], "hincrby") {
+            |c, cmd, out| c.do_hincrby(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "hscan") {
+            |c, cmd, out| c.do_hscan(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "lpush") {
+            |c, cmd, out| c.do_push(cmd, out, true)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "rpush") {
+            |c, cmd, out| c.do_push(cmd, out, false)
+        } else if (cmd.len() == 2 || cmd.len() == 3) && cmd_is(&cmd[0], "lpop") {
+            |c, cmd, out| c.do_pop(cmd, out, true)
+        } else if (cmd.len() == 2 || cmd.len() == 3) && cmd_is(&cmd[0], "rpop") {
+            |c, cmd, out| c.do_pop(cmd, out, false)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "llen") {
+            |c, cmd, out| c.do_llen(cmd, out)
+        } else if cmd.len() == 4 && cmd_is(&cmd[0], "lrange") {
+            |c, cmd, out| c.do_lrange(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "lindex") {
+            |c, cmd, out| c.do_lindex(cmd, out)
+        } else if cmd.len() == 4 && cmd_is(&cmd[0], "lset") {
+            |c, cmd, out| c.do_lset(cmd, out)
+        } else if cmd.len() == 5 && cmd_is(&cmd[0], "linsert") {
+            |c, cmd, out| c.do_linsert(cmd, out)
+        } else if cmd.len() == 4 && cmd_is(&cmd[0], "lrem") {
+            |c, cmd, out| c.do_lrem(cmd, out)
+        } else if cmd.len() == 4 && cmd_is(&cmd[0], "ltrim") {
+            |c, cmd, out| c.do_ltrim(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "blpop") {
+            |c, cmd, out| c.do_blpop(cmd, out, true)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "brpop") {
+            |c, cmd, out| c.do_blpop(cmd, out, false)
+        } else if (cmd.len() == 5 && cmd_is(&cmd[0], "lmove"))
+            || (cmd.len() == 6 && cmd_is(&cmd[0], "blmove"))
+        {
+            |c, cmd, out| c.do_lmove(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "sadd") {
+            |c, cmd, out| c.do_sadd(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "srem") {
+            |c, cmd, out| c.do_srem(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "sismember") {
+            |c, cmd, out| c.do_sismember(cmd, out, false)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "smismember") {
+            |c, cmd, out| c.do_sismember(cmd, out, true)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "scard") {
+            |c, cmd, out| c.do_scard(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "smembers") {
+            |c, cmd, out| c.do_setop(cmd, out, SetOp::Union, false)
+        } else if (cmd.len() == 2 || cmd.len() == 3) && cmd_is(&cmd[0], "spop") {
+            |c, cmd, out| c.do_spop(cmd, out)
+        } else if (cmd.len() == 2 || cmd.len() == 3) && cmd_is(&cmd[0], "srandmember") {
+            |c, cmd, out| c.do_srandmember(cmd, out)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "sinter") {
+            |c, cmd, out| c.do_setop(cmd, out, SetOp::Inter, false)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "sunion") {
+            |c, cmd, out| c.do_setop(cmd, out, SetOp::Union, false)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "sdiff") {
+            |c, cmd, out| c.do_setop(cmd, out, SetOp::Diff, false)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "sinterstore") {
+            |c, cmd, out| c.do_setop(cmd, out, SetOp::Inter, true)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "sunionstore") {
+            |c, cmd, out| c.do_setop(cmd, out, SetOp::Union, true)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "sdiffstore") {
+            |c, cmd, out| c.do_setop(cmd, out, SetOp::Diff, true)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "sscan") {
+            |c, cmd, out| c.do_sscan(cmd, out)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "subscribe") {
+            |c, cmd, out| c.do_subscribe(cmd, out, false)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "psubscribe") {
+            |c, cmd, out| c.do_subscribe(cmd, out, true)
+        } else if cmd_is(&cmd[0], "unsubscribe") {
+            |c, cmd, out| c.do_unsubscribe(cmd, out, false)
+        } else if cmd_is(&cmd[0], "punsubscribe") {
+            |c, cmd, out| c.do_unsubscribe(cmd, out, true)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "publish") {
+            |c, cmd, out| c.do_publish(cmd, out)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "pubsub") {
+            |c, cmd, out| c.do_pubsub(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "memory") && cmd_is(&cmd[1], "stats") {
+            |c, cmd, out| c.do_memory_stats(cmd, out)
+        } else if cmd.len() >= 3 && cmd_is(&cmd[0], "config") && cmd_is(&cmd[1], "get") {
+            |c, cmd, out| c.do_config_get(cmd, out)
+        } else if cmd.len() >= 4
+            && cmd.len().is_multiple_of(2)
+            && cmd_is(&cmd[0], "config")
+            && cmd_is(&cmd[1], "set")
+        {
+            |c, cmd, out| c.do_config_set(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "config") && cmd_is(&cmd[1], "rewrite") {
+            |c, cmd, out| c.do_config_rewrite(cmd, out)
+        } else if cmd_is(&cmd[0], "info") {
+            |c, cmd, out| c.do_info(cmd, out)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "acl") {
+            |c, cmd, out| c.do_acl(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "replicaof") {
+            |c, cmd, out| c.do_replicaof(cmd, out)
+        } else if cmd.len() == 3 && cmd_is(&cmd[0], "psync") {
+            |c, cmd, out| c.do_psync(cmd, out)
+        } else if cmd.len() == 4 && cmd_is(&cmd[0], "fullresync") {
+            |c, cmd, out| c.do_fullresync(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "snapchunk") {
+            |c, cmd, out| c.do_snapchunk(cmd, out)
+        } else if cmd.len() == 2 && cmd_is(&cmd[0], "continue") {
+            |c, cmd, out| c.do_continue(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "role") {
+            |c, cmd, out| c.do_role(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "multi") {
+            |c, cmd, out| c.do_multi(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "exec") {
+            |c, cmd, out| c.do_exec(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "discard") {
+            |c, cmd, out| c.do_discard(cmd, out)
+        } else if cmd.len() >= 2 && cmd_is(&cmd[0], "watch") {
+            |c, cmd, out| c.do_watch(cmd, out)
+        } else if cmd.len() == 1 && cmd_is(&cmd[0], "unwatch") {
+            |c, cmd, out| c.do_unwatch(cmd, out)
         } else {
-            self.out_err(out,ErrorCode::RES_ERR,&String::from("Unknown CMD"));
+            return None;
+        };
+        Some(handler)
+    }
+    // ping [message]
+    fn do_ping(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        // subscribers get the reply in the shape of a message
+        if self.is_subscriber() && self.proto != Proto::Resp3 {
+            self.out_arr(out, 2);
+            self.out_str(out, b"pong");
+            self.out_str(out, cmd.get(1).map_or(&b""[..], Vec::as_slice));
+            return;
         }
+        match cmd.get(1) {
+            Some(msg) => self.out_str(out, msg),
+            None => self.out_status(out, "PONG"),
+        }
+    }
 
-        std::io::Result::Ok(())
+    // hello [protover]
+    // hello [protover [auth username password]]
+    fn do_hello(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let auth = cmd.len() == 5 && cmd_is(&cmd[2], "auth");
+        if cmd.len() > 2 && !auth {
+            self.out_err(out, ErrorCode::RES_ERR, "syntax error");
+            return;
+        }
+        if auth && !self.authenticate(&cmd[3], &cmd[4], out) {
+            return;
+        }
+        if let Some(ver) = cmd.get(1) {
+            let proto = match str2int(ver) {
+                Some(2) => Proto::Resp2,
+                Some(3) => Proto::Resp3,
+                _ => {
+                    self.out_err(out, ErrorCode::RES_ERR, "unsupported protocol version");
+                    return;
+                }
+            };
+            // the binary protocol has no versions to switch between
+            if self.is_resp() {
+                self.proto = proto;
+            }
+        }
+
+        self.out_map(out, 5);
+        self.out_str(out, b"server");
+        self.out_str(out, b"rustis");
+        self.out_str(out, b"version");
+        self.out_str(out, env!("CARGO_PKG_VERSION").as_bytes());
+        self.out_str(out, b"proto");
+        self.out_int(out, if self.proto == Proto::Resp3 { 3 } else { 2 });
+        self.out_str(out, b"mode");
+        self.out_str(out, b"standalone");
+        self.out_str(out, b"role");
+        let role = if G_REPL.lock().unwrap().is_replica() {
+            "replica"
+        } else {
+            "master"
+        };
+        self.out_str(out, role.as_bytes());
     }
-    fn do_keys(&mut self,cmd:&Vec<String>,out: &mut Vec<u8>){
-        let map=G_MAP.lock().unwrap();
-        let len=map.len();
-        let keys=map.keys();
-        self.out_arr(out,len);
-        for key in keys{
-            self.out_str(out,key);
+
+    // auth [username] password
+    fn do_auth(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let (name, password) = match cmd {
+            [_, password] => (&b"default"[..], password),
+            [_, name, password] => (&name[..], password),
+            _ => unreachable!("routed with 2 or 3 arguments"),
+        };
+        if self.authenticate(name, password, out) {
+            self.out_ok(out);
+        }
+    }
+
+    /// Switches to the user `name` if `password` is one of its passwords,
+    /// otherwise replies with an error and returns `false`.
+    fn authenticate(&mut self, name: &[u8], password: &[u8], out: &mut Vec<u8>) -> bool {
+        let name = String::from_utf8_lossy(name).into_owned();
+        if !G_ACL.lock().unwrap().authenticate(&name, password) {
+            let msg = "invalid username-password pair or user is disabled.";
+            self.out_err(out, ErrorCode::RES_WRONGPASS, msg);
+            return false;
         }
+        self.user = Some(name);
+        true
+    }
 
+    fn do_keys(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        let keys: Vec<&Vec<u8>> = map.keys().collect();
+        self.out_arr(out, keys.len());
+        for key in keys {
+            self.out_str(out, key);
+        }
     }
-    fn out_arr(&mut self,out: &mut Vec<u8>,len:usize){
+    fn is_resp(&self) -> bool {
+        matches!(self.proto, Proto::Resp2 | Proto::Resp3)
+    }
+    fn out_arr(&mut self, out: &mut Vec<u8>, len: usize) {
+        if self.is_resp() {
+            return resp::put_array(out, len);
+        }
         out.push(Serialization::SER_ARR as u8);
         out.extend((len as u32).to_le_bytes());
     }
-    fn out_err(&mut self,out:&mut Vec<u8>,err:ErrorCode,msg:&String){
+    /// A map of `len` pairs, a flat array of keys and values unless the
+    /// client speaks RESP3.
+    fn out_map(&mut self, out: &mut Vec<u8>, len: usize) {
+        if self.is_resp() {
+            return resp::put_map(out, len, self.proto == Proto::Resp3);
+        }
+        self.out_arr(out, len * 2);
+    }
+    fn out_err(&mut self, out: &mut Vec<u8>, err: ErrorCode, msg: &str) {
+        if self.is_resp() {
+            let code = match err {
+                ErrorCode::RES_TYPE => "WRONGTYPE",
+                ErrorCode::RES_ABORT => "EXECABORT",
+                ErrorCode::RES_OOM => "OOM",
+                ErrorCode::RES_READONLY => "READONLY",
+                ErrorCode::RES_NOAUTH => "NOAUTH",
+                ErrorCode::RES_NOPERM => "NOPERM",
+                ErrorCode::RES_WRONGPASS => "WRONGPASS",
+                _ => "ERR",
+            };
+            return resp::put_error(out, code, msg);
+        }
         out.push(Serialization::SER_ERR as u8);
         out.extend((err as u32).to_le_bytes());
-        let len=msg.as_bytes().len();
+        let len = msg.len();
         out.extend((len as u32).to_le_bytes());
         out.extend(msg.as_bytes());
-
     }
-    fn out_int(&mut self,out: &mut Vec<u8>,n:u32){
+    fn out_int(&mut self, out: &mut Vec<u8>, n: i64) {
+        if self.is_resp() {
+            return resp::put_int(out, n);
+        }
         out.push(Serialization::SER_INT as u8);
         out.extend((n).to_le_bytes());
     }
-    fn out_str(&mut self,out: &mut Vec<u8>,s:&String){
+    fn out_dbl(&mut self, out: &mut Vec<u8>, d: f64) {
+        if self.is_resp() {
+            return resp::put_double(out, d, self.proto == Proto::Resp3);
+        }
+        out.push(Serialization::SER_DBL as u8);
+        out.extend(d.to_le_bytes());
+    }
+    fn out_str(&mut self, out: &mut Vec<u8>, s: &[u8]) {
+        if self.is_resp() {
+            return resp::put_bulk(out, s);
+        }
         out.push(Serialization::SER_STR as u8);
-        let len=s.as_bytes().len();
+        let len = s.len();
         out.extend((len as u32).to_le_bytes());
-        out.extend(s.as_bytes());
+        out.extend(s);
+    }
+    /// A status reply, a simple string in RESP.
+    fn out_status(&mut self, out: &mut Vec<u8>, s: &str) {
+        if self.is_resp() {
+            return resp::put_simple(out, s);
+        }
+        self.out_str(out, s.as_bytes());
     }
-    fn out_nil(&mut self,out: &mut Vec<u8>){
+    /// The reply of a command that only succeeds or fails. Binary clients
+    /// have always got a nil for it.
+    fn out_ok(&mut self, out: &mut Vec<u8>) {
+        if self.is_resp() {
+            return resp::put_simple(out, "OK");
+        }
+        self.out_nil(out);
+    }
+    fn out_nil(&mut self, out: &mut Vec<u8>) {
+        if self.is_resp() {
+            return resp::put_null(out, self.proto == Proto::Resp3);
+        }
         out.push(Serialization::SER_NIL as u8);
     }
+    /// A missing array, as BLPOP and BRPOP answer when they time out.
+    fn out_null_arr(&mut self, out: &mut Vec<u8>) {
+        if self.is_resp() {
+            return resp::put_null_array(out, self.proto == Proto::Resp3);
+        }
+        out.push(Serialization::SER_NIL as u8);
+    }
+
+    fn do_get(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+
+        let val = map.get(&cmd[1]);
+        let counter = if val.is_some() {
+            &info::KEYSPACE_HITS
+        } else {
+            &info::KEYSPACE_MISSES
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        match val {
+            None => self.out_nil(out),
+            Some(Value::Str(val)) => self.out_str(out, val),
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect string type"),
+        }
+    }
 
+    // set key value [ex seconds | px milliseconds]
+    fn do_set(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut deadline = None;
+        if cmd.len() == 5 {
+            let unit = if cmd_is(&cmd[3], "ex") {
+                1000
+            } else if cmd_is(&cmd[3], "px") {
+                1
+            } else {
+                self.out_err(out, ErrorCode::RES_ARG, "syntax error");
+                return;
+            };
+            match str2int(&cmd[4]) {
+                Some(ttl) if ttl > 0 => {
+                    deadline = Some(now_ms().saturating_add((ttl as u64).saturating_mul(unit)))
+                }
+                _ => {
+                    self.out_err(out, ErrorCode::RES_ARG, "invalid expire time");
+                    return;
+                }
+            }
+        }
 
+        let mut map = G_MAP.lock().unwrap();
+        map.insert(&cmd[1], Value::Str(cmd[2].clone()));
+        propagate(&[&cmd[0], &cmd[1], &cmd[2]]);
+        notify::notify(notify::STRING, "set", &cmd[1]);
+        if let Some(deadline) = deadline {
+            map.expire_at(&cmd[1], deadline);
+            propagate(&[b"pexpireat", &cmd[1], deadline.to_string().as_bytes()]);
+            notify::notify(notify::GENERIC, "expire", &cmd[1]);
+        }
+        self.out_ok(out);
+    }
 
+    fn do_del(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut map = G_MAP.lock().unwrap();
+        let v = map.remove(&cmd[1]);
+        if v.is_none() {
+            self.out_int(out, 0);
+        } else {
+            propagate(&[&cmd[0], &cmd[1]]);
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+            self.out_int(out, 1);
+        }
+    }
 
+    // incr key, decr key, incrby key delta, decrby key delta
+    fn do_incr(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, sign: i64) {
+        let n = match cmd.get(2) {
+            None => Some(1),
+            Some(arg) => str2int(arg),
+        };
+        let Some(n) = n else {
+            self.out_err(out, ErrorCode::RES_ARG, "expect int");
+            return;
+        };
+        // DECRBY of i64::MIN has no positive counterpart to add
+        let Some(delta) = n.checked_mul(sign) else {
+            self.out_err(
+                out,
+                ErrorCode::RES_ERR,
+                "increment or decrement would overflow",
+            );
+            return;
+        };
 
+        let mut map = G_MAP.lock().unwrap();
+        // updated in place so the key keeps its deadline
+        let val = match map.get_or_insert_with(&cmd[1], || Value::Str(b"0".to_vec())) {
+            Value::Str(val) => val,
+            _ => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect string type");
+                return;
+            }
+        };
+        let n = match str2int(val) {
+            Some(n) => n,
+            None => {
+                self.out_err(
+                    out,
+                    ErrorCode::RES_ERR,
+                    "value is not an integer or out of range",
+                );
+                return;
+            }
+        };
+        let Some(n) = n.checked_add(delta) else {
+            self.out_err(
+                out,
+                ErrorCode::RES_ERR,
+                "increment or decrement would overflow",
+            );
+            return;
+        };
+        *val = n.to_string().into_bytes();
+        propagate(&[b"incrby", &cmd[1], delta.to_string().as_bytes()]);
+        notify::notify(notify::STRING, "incrby", &cmd[1]);
+        self.out_int(out, n);
+    }
 
+    // incrbyfloat key delta
+    fn do_incrbyfloat(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some(delta) = str2dbl(&cmd[2]) else {
+            self.out_err(out, ErrorCode::RES_ARG, "expect float");
+            return;
+        };
 
+        let mut map = G_MAP.lock().unwrap();
+        let val = match map.get_or_insert_with(&cmd[1], || Value::Str(b"0".to_vec())) {
+            Value::Str(val) => val,
+            _ => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect string type");
+                return;
+            }
+        };
+        let d = match str2dbl(val) {
+            Some(d) => d + delta,
+            None => {
+                self.out_err(out, ErrorCode::RES_ERR, "value is not a valid float");
+                return;
+            }
+        };
+        if !d.is_finite() {
+            self.out_err(
+                out,
+                ErrorCode::RES_ERR,
+                "increment would produce NaN or Infinity",
+            );
+            return;
+        }
+        // logged as the resulting value, since SET drops the deadline it is
+        // logged again
+        *val = resp::fmt_double(d).into_bytes();
+        let val = val.clone();
+        propagate(&[b"set", &cmd[1], &val]);
+        if let Some(deadline) = map.deadline(&cmd[1]) {
+            propagate(&[b"pexpireat", &cmd[1], deadline.to_string().as_bytes()]);
+        }
+        notify::notify(notify::STRING, "incrbyfloat", &cmd[1]);
+        // like GET, the new value is returned as a string
+        self.out_str(out, &val);
+    }
 
+    // expire key seconds, pexpire key milliseconds
+    fn do_expire(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, unit: i64) {
+        let ttl = match str2int(&cmd[2]) {
+            Some(ttl) => ttl.saturating_mul(unit),
+            None => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect int");
+                return;
+            }
+        };
+        // a non-positive ttl deletes the key
+        let deadline = now_ms().saturating_add_signed(ttl);
+        self.expire_key(&cmd[1], deadline, out);
+    }
 
+    // pexpireat key unix-time-milliseconds
+    fn do_pexpireat(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        match str2int(&cmd[2]) {
+            Some(deadline) => self.expire_key(&cmd[1], deadline.max(0) as u64, out),
+            None => self.out_err(out, ErrorCode::RES_ARG, "expect int"),
+        }
+    }
 
+    fn expire_key(&mut self, key: &[u8], deadline: u64, out: &mut Vec<u8>) {
+        let mut map = G_MAP.lock().unwrap();
+        let ok = map.expire_at(key, deadline);
+        if ok {
+            propagate(&[b"pexpireat", key, deadline.to_string().as_bytes()]);
+            // a deadline in the past deletes the key right away
+            let event = if map.get(key).is_some() {
+                "expire"
+            } else {
+                "del"
+            };
+            notify::notify(notify::GENERIC, event, key);
+        }
+        self.out_int(out, ok as i64);
+    }
+
+    // ttl key, pttl key
+    fn do_ttl(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, unit: u64) {
+        let map = G_MAP.lock().unwrap();
+        if map.get(&cmd[1]).is_none() {
+            self.out_int(out, -2);
+            return;
+        }
+        match map.deadline(&cmd[1]) {
+            None => self.out_int(out, -1),
+            Some(deadline) => {
+                let left = deadline.saturating_sub(now_ms());
+                // round to the nearest unit like redis does
+                self.out_int(out, ((left + unit / 2) / unit) as i64);
+            }
+        }
+    }
+
+    // persist key
+    fn do_persist(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut map = G_MAP.lock().unwrap();
+        let ok = map.persist(&cmd[1]);
+        if ok {
+            propagate(&[&cmd[0], &cmd[1]]);
+            notify::notify(notify::GENERIC, "persist", &cmd[1]);
+        }
+        self.out_int(out, ok as i64);
+    }
+
+    fn do_save(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if snapshot::BGSAVE_RUNNING.load(Ordering::Acquire) {
+            self.out_err(
+                out,
+                ErrorCode::RES_ERR,
+                "background save already in progress",
+            );
+            return;
+        }
+        let path = G_CONFIG.read().unwrap().snapshot_path.clone();
+        match snapshot::write(&path, &encode_snapshot()) {
+            Ok(()) => self.out_ok(out),
+            Err(e) => self.out_err(out, ErrorCode::RES_ERR, &e.to_string()),
+        }
+    }
+
+    // a forked child encodes and writes the snapshot, the event loop only
+    // pays for the fork
+    fn do_bgsave(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let path = G_CONFIG.read().unwrap().snapshot_path.clone();
+        let map = G_MAP.lock().unwrap();
+        let aof_offset = G_AOF.lock().unwrap().as_ref().map_or(0, |aof| aof.len());
+        match snapshot::write_in_background(&path, &map, aof_offset) {
+            Ok(true) => self.out_status(out, "Background saving started"),
+            Ok(false) => self.out_err(
+                out,
+                ErrorCode::RES_ERR,
+                "background save already in progress",
+            ),
+            Err(e) => self.out_err(out, ErrorCode::RES_ERR, &e.to_string()),
+        }
+    }
+
+    // zadd key score member [score member ...]
+    fn do_zadd(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if !cmd.len().is_multiple_of(2) {
+            self.out_err(out, ErrorCode::RES_ARG, "expect score member pairs");
+            return;
+        }
+        let mut pairs = Vec::with_capacity((cmd.len() - 2) / 2);
+        for pair in cmd[2..].chunks(2) {
+            match str2dbl(&pair[0]) {
+                Some(score) => pairs.push((score, &pair[1])),
+                None => {
+                    self.out_err(out, ErrorCode::RES_ARG, "expect float");
+                    return;
+                }
+            }
+        }
+
+        let mut map = G_MAP.lock().unwrap();
+        let val = map.get_or_insert_with(&cmd[1], || Value::ZSet(ZSet::new()));
+        let zset = match val {
+            Value::ZSet(zset) => zset,
+            _ => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect zset");
+                return;
+            }
+        };
+        let added = pairs
+            .iter()
+            .filter(|(score, name)| zset.add(name, *score))
+            .count();
+        propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        notify::notify(notify::ZSET, "zadd", &cmd[1]);
+        self.out_int(out, added as i64);
+    }
+
+    // zrem key member [member ...]
+    fn do_zrem(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut map = G_MAP.lock().unwrap();
+        let zset = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_int(out, 0);
+                return;
+            }
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect zset");
+                return;
+            }
+        };
+        let removed = cmd[2..].iter().filter(|name| zset.remove(name)).count();
+        let emptied = zset.len() == 0;
+        if emptied {
+            map.remove(&cmd[1]);
+        }
+        if removed > 0 {
+            propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+            notify::notify(notify::ZSET, "zrem", &cmd[1]);
+        }
+        if emptied {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        }
+        self.out_int(out, removed as i64);
+    }
+
+    // zscore key member
+    fn do_zscore(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_nil(out),
+            Some(Value::ZSet(zset)) => match zset.score(&cmd[2]) {
+                Some(score) => self.out_dbl(out, score),
+                None => self.out_nil(out),
+            },
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect zset"),
+        }
+    }
+
+    // zrank key member
+    fn do_zrank(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_nil(out),
+            Some(Value::ZSet(zset)) => match zset.rank(&cmd[2]) {
+                Some(rank) => self.out_int(out, rank as i64),
+                None => self.out_nil(out),
+            },
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect zset"),
+        }
+    }
+
+    // zcard key
+    fn do_zcard(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_int(out, 0),
+            Some(Value::ZSet(zset)) => self.out_int(out, zset.len() as i64),
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect zset"),
+        }
+    }
+
+    /// Writes (member, score) pairs as a flat array, with the scores only
+    /// when `withscores` is set.
+    fn out_zrange(&mut self, out: &mut Vec<u8>, items: &[(&[u8], f64)], withscores: bool) {
+        self.out_arr(out, items.len() * if withscores { 2 } else { 1 });
+        for (name, score) in items {
+            self.out_str(out, name);
+            if withscores {
+                self.out_dbl(out, *score);
+            }
+        }
+    }
+
+    // zrange key start stop [withscores]
+    fn do_zrange(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let (start, stop) = match (str2int(&cmd[2]), str2int(&cmd[3])) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect int");
+                return;
+            }
+        };
+        let withscores = match &cmd[4..] {
+            [] => false,
+            [opt] if cmd_is(opt, "withscores") => true,
+            _ => {
+                self.out_err(out, ErrorCode::RES_ARG, "syntax error");
+                return;
+            }
+        };
+
+        let map = G_MAP.lock().unwrap();
+        let zset = match map.get(&cmd[1]) {
+            None => {
+                self.out_arr(out, 0);
+                return;
+            }
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect zset");
+                return;
+            }
+        };
+
+        let items: Vec<(&[u8], f64)> = match index_range(start, stop, zset.len()) {
+            None => vec![],
+            Some((start, stop)) => zset.iter_from(start).take(stop - start + 1).collect(),
+        };
+        self.out_zrange(out, &items, withscores);
+    }
+
+    // zrangebyscore key min max [withscores] [limit offset count]
+    fn do_zrangebyscore(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let ((min, min_ex), (max, max_ex)) = match (str2bound(&cmd[2]), str2bound(&cmd[3])) {
+            (Some(min), Some(max)) => (min, max),
+            _ => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect float");
+                return;
+            }
+        };
+        let mut withscores = false;
+        let mut offset = 0_usize;
+        let mut limit = usize::MAX;
+        let mut i = 4;
+        while i < cmd.len() {
+            if cmd_is(&cmd[i], "withscores") {
+                withscores = true;
+                i += 1;
+            } else if cmd_is(&cmd[i], "limit") && i + 2 < cmd.len() {
+                match (str2int(&cmd[i + 1]), str2int(&cmd[i + 2])) {
+                    (Some(o), Some(c)) if o >= 0 => {
+                        offset = o as usize;
+                        // a negative count means no limit
+                        limit = if c < 0 { usize::MAX } else { c as usize };
+                    }
+                    _ => {
+                        self.out_err(out, ErrorCode::RES_ARG, "expect int");
+                        return;
+                    }
+                }
+                i += 3;
+            } else {
+                self.out_err(out, ErrorCode::RES_ARG, "syntax error");
+                return;
+            }
+        }
+
+        let map = G_MAP.lock().unwrap();
+        let zset = match map.get(&cmd[1]) {
+            None => {
+                self.out_arr(out, 0);
+                return;
+            }
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect zset");
+                return;
+            }
+        };
+
+        let start = zset.seek(min, min_ex).saturating_add(offset);
+        let items: Vec<(&[u8], f64)> = zset
+            .iter_from(start)
+            .take_while(|(_, score)| if max_ex { *score < max } else { *score <= max })
+            .take(limit)
+            .collect();
+        self.out_zrange(out, &items, withscores);
+    }
+
+    // hset key field value [field value ...]
+    fn do_hset(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if !cmd.len().is_multiple_of(2) {
+            self.out_err(out, ErrorCode::RES_ARG, "expect field value pairs");
+            return;
+        }
+        let mut map = G_MAP.lock().unwrap();
+        let hash = match map.get_or_insert_with(&cmd[1], || Value::Hash(HMap::new())) {
+            Value::Hash(hash) => hash,
+            _ => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect hash");
+                return;
+            }
+        };
+        let added = cmd[2..]
+            .chunks(2)
+            .filter(|pair| hash.hm_insert(pair[0].clone(), pair[1].clone()).is_none())
+            .count();
+        propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        notify::notify(notify::HASH, "hset", &cmd[1]);
+        self.out_int(out, added as i64);
+    }
+
+    // hget key field
+    fn do_hget(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_nil(out),
+            Some(Value::Hash(hash)) => match hash.hm_lookup(&cmd[2]) {
+                Some(val) => self.out_str(out, val),
+                None => self.out_nil(out),
+            },
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect hash"),
+        }
+    }
+
+    // hmget key field [field ...]
+    fn do_hmget(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        let hash = match map.get(&cmd[1]) {
+            None => None,
+            Some(Value::Hash(hash)) => Some(hash),
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect hash");
+                return;
+            }
+        };
+        self.out_arr(out, cmd.len() - 2);
+        for field in &cmd[2..] {
+            match hash.and_then(|hash| hash.hm_lookup(field)) {
+                Some(val) => self.out_str(out, val),
+                None => self.out_nil(out),
+            }
+        }
+    }
+
+    // hdel key field [field ...]
+    fn do_hdel(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut map = G_MAP.lock().unwrap();
+        let hash = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_int(out, 0);
+                return;
+            }
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect hash");
+                return;
+            }
+        };
+        let removed = cmd[2..]
+            .iter()
+            .filter(|field| hash.hm_delete(*field).is_some())
+            .count();
+        let emptied = hash.hm_size() == 0;
+        if emptied {
+            map.remove(&cmd[1]);
+        }
+        if removed > 0 {
+            propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+            notify::notify(notify::HASH, "hdel", &cmd[1]);
+        }
+        if emptied {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        }
+        self.out_int(out, removed as i64);
+    }
+
+    // hexists key field
+    fn do_hexists(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_int(out, 0),
+            Some(Value::Hash(hash)) => self.out_int(out, hash.hm_lookup(&cmd[2]).is_some() as i64),
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect hash"),
+        }
+    }
+
+    // hlen key
+    fn do_hlen(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_int(out, 0),
+            Some(Value::Hash(hash)) => self.out_int(out, hash.hm_size() as i64),
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect hash"),
+        }
+    }
+
+    // hkeys key, hvals key, hgetall key
+    fn do_hgetall(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, keys: bool, vals: bool) {
+        let map = G_MAP.lock().unwrap();
+        let hash = match map.get(&cmd[1]) {
+            None => {
+                self.out_arr(out, 0);
+                return;
+            }
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect hash");
+                return;
+            }
+        };
+        if keys && vals {
+            self.out_map(out, hash.hm_size());
+        } else {
+            self.out_arr(out, hash.hm_size());
+        }
+        for (field, val) in hash.iter() {
+            if keys {
+                self.out_str(out, field);
+            }
+            if vals {
+                self.out_str(out, val);
+            }
+        }
+    }
+
+    // hincrby key field delta
+    fn do_hincrby(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some(delta) = str2int(&cmd[3]) else {
+            self.out_err(out, ErrorCode::RES_ARG, "expect int");
+            return;
+        };
+        let mut map = G_MAP.lock().unwrap();
+        let hash = match map.get_or_insert_with(&cmd[1], || Value::Hash(HMap::new())) {
+            Value::Hash(hash) => hash,
+            _ => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect hash");
+                return;
+            }
+        };
+        let n = match hash.hm_lookup(&cmd[2]) {
+            None => 0,
+            Some(val) => match str2int(val) {
+                Some(n) => n,
+                None => {
+                    self.out_err(out, ErrorCode::RES_ERR, "hash value is not an integer");
+                    return;
+                }
+            },
+        };
+        let Some(n) = n.checked_add(delta) else {
+            self.out_err(
+                out,
+                ErrorCode::RES_ERR,
+                "increment or decrement would overflow",
+            );
+            return;
+        };
+        hash.hm_insert(cmd[2].clone(), n.to_string().into_bytes());
+        propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        notify::notify(notify::HASH, "hincrby", &cmd[1]);
+        self.out_int(out, n);
+    }
+
+    /// Parses the `cursor [match pattern] [count count]` arguments of the
+    /// SCAN family, replying with an error if they are invalid.
+    fn scan_args<'a>(
+        &mut self,
+        args: &'a [Vec<u8>],
+        out: &mut Vec<u8>,
+    ) -> Option<(u64, Option<&'a [u8]>, usize)> {
+        let Some(cursor) = std::str::from_utf8(&args[0])
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+        else {
+            self.out_err(out, ErrorCode::RES_ARG, "invalid cursor");
+            return None;
+        };
+        let mut pattern: Option<&[u8]> = None;
+        let mut count = 10_usize;
+        let mut i = 1;
+        while i < args.len() {
+            if cmd_is(&args[i], "match") && i + 1 < args.len() {
+                pattern = Some(&args[i + 1]);
+            } else if cmd_is(&args[i], "count") && i + 1 < args.len() {
+                match str2int(&args[i + 1]) {
+                    Some(c) if c > 0 => count = c as usize,
+                    _ => {
+                        self.out_err(out, ErrorCode::RES_ARG, "expect positive int");
+                        return None;
+                    }
+                }
+            } else {
+                self.out_err(out, ErrorCode::RES_ARG, "syntax error");
+                return None;
+            }
+            i += 2;
+        }
+        Some((cursor, pattern, count))
+    }
+
+    // hscan key cursor [match pattern] [count count]
+    fn do_hscan(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some((mut cursor, pattern, count)) = self.scan_args(&cmd[2..], out) else {
+            return;
+        };
+
+        let map = G_MAP.lock().unwrap();
+        let mut items: Vec<(&[u8], &[u8])> = Vec::new();
+        match map.get(&cmd[1]) {
+            None => cursor = 0,
+            Some(Value::Hash(hash)) => {
+                // `count` is a hint of how many fields to look at, the
+                // pattern is applied afterwards like redis does
+                let mut nseen = 0;
+                loop {
+                    cursor = hash.hm_scan(cursor, |field, val| {
+                        nseen += 1;
+                        if pattern.is_none_or(|p| glob::matches(p, field)) {
+                            items.push((field, val));
+                        }
+                    });
+                    if cursor == 0 || nseen >= count {
+                        break;
+                    }
+                }
+            }
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect hash");
+                return;
+            }
+        }
+
+        self.out_arr(out, 2);
+        self.out_str(out, cursor.to_string().as_bytes());
+        self.out_arr(out, items.len() * 2);
+        for (field, val) in items {
+            self.out_str(out, field);
+            self.out_str(out, val);
+        }
+    }
+
+    // lpush key value [value ...], rpush key value [value ...]
+    fn do_push(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, front: bool) {
+        let mut map = G_MAP.lock().unwrap();
+        let list = match map.get_or_insert_with(&cmd[1], || Value::List(List::new())) {
+            Value::List(list) => list,
+            _ => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return;
+            }
+        };
+        for val in &cmd[2..] {
+            if front {
+                list.push_front(val.clone());
+            } else {
+                list.push_back(val.clone());
+            }
+        }
+        let len = list.len();
+        propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        notify::notify(notify::LIST, if front { "lpush" } else { "rpush" }, &cmd[1]);
+        blocking::signal_ready(&cmd[1]);
+        self.out_int(out, len as i64);
+    }
+
+    // lpop key [count], rpop key [count]
+    fn do_pop(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, front: bool) {
+        let count = match cmd.get(2).map(|c| str2int(c)) {
+            None => None,
+            Some(Some(c)) if c >= 0 => Some(c as usize),
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect non-negative int");
+                return;
+            }
+        };
+
+        let mut map = G_MAP.lock().unwrap();
+        let list = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_nil(out);
+                return;
+            }
+            Some(Value::List(list)) => list,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return;
+            }
+        };
+        let mut vals = Vec::new();
+        while vals.len() < count.unwrap_or(1) {
+            let val = if front {
+                list.pop_front()
+            } else {
+                list.pop_back()
+            };
+            match val {
+                Some(val) => vals.push(val),
+                None => break,
+            }
+        }
+        let emptied = list.len() == 0;
+        if emptied {
+            map.remove(&cmd[1]);
+        }
+        if !vals.is_empty() {
+            propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+            notify::notify(notify::LIST, if front { "lpop" } else { "rpop" }, &cmd[1]);
+        }
+        if emptied {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        }
+
+        // without a count a single element is returned, not an array
+        if count.is_some() {
+            self.out_arr(out, vals.len());
+        }
+        for val in &vals {
+            self.out_str(out, val);
+        }
+    }
+
+    // llen key
+    fn do_llen(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_int(out, 0),
+            Some(Value::List(list)) => self.out_int(out, list.len() as i64),
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect list"),
+        }
+    }
+
+    // lrange key start stop
+    fn do_lrange(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let (start, stop) = match (str2int(&cmd[2]), str2int(&cmd[3])) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect int");
+                return;
+            }
+        };
+        let map = G_MAP.lock().unwrap();
+        let list = match map.get(&cmd[1]) {
+            None => {
+                self.out_arr(out, 0);
+                return;
+            }
+            Some(Value::List(list)) => list,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return;
+            }
+        };
+        match index_range(start, stop, list.len()) {
+            None => self.out_arr(out, 0),
+            Some((start, stop)) => {
+                self.out_arr(out, stop - start + 1);
+                for val in list.iter_from(start).take(stop - start + 1) {
+                    self.out_str(out, val);
+                }
+            }
+        }
+    }
+
+    // lindex key index
+    fn do_lindex(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some(i) = str2int(&cmd[2]) else {
+            self.out_err(out, ErrorCode::RES_ARG, "expect int");
+            return;
+        };
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_nil(out),
+            Some(Value::List(list)) => match list_index(i, list.len()).and_then(|i| list.get(i)) {
+                Some(val) => self.out_str(out, val),
+                None => self.out_nil(out),
+            },
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect list"),
+        }
+    }
+
+    // lset key index value
+    fn do_lset(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some(i) = str2int(&cmd[2]) else {
+            self.out_err(out, ErrorCode::RES_ARG, "expect int");
+            return;
+        };
+        let mut map = G_MAP.lock().unwrap();
+        let list = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_err(out, ErrorCode::RES_NX, "no such key");
+                return;
+            }
+            Some(Value::List(list)) => list,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return;
+            }
+        };
+        match list_index(i, list.len()) {
+            Some(i) => {
+                list.set(i, cmd[3].clone());
+                propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+                notify::notify(notify::LIST, "lset", &cmd[1]);
+                self.out_ok(out);
+            }
+            None => self.out_err(out, ErrorCode::RES_ERR, "index out of range"),
+        }
+    }
+
+    // linsert key before|after pivot value
+    fn do_linsert(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let after = if cmd_is(&cmd[2], "after") {
+            true
+        } else if cmd_is(&cmd[2], "before") {
+            false
+        } else {
+            self.out_err(out, ErrorCode::RES_ARG, "syntax error");
+            return;
+        };
+        let mut map = G_MAP.lock().unwrap();
+        let list = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_int(out, 0);
+                return;
+            }
+            Some(Value::List(list)) => list,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return;
+            }
+        };
+        match list.position(&cmd[3]) {
+            Some(i) => {
+                list.insert(i + after as usize, cmd[4].clone());
+                let len = list.len();
+                propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+                notify::notify(notify::LIST, "linsert", &cmd[1]);
+                self.out_int(out, len as i64);
+            }
+            // the pivot was not found
+            None => self.out_int(out, -1),
+        }
+    }
+
+    // lrem key count value
+    fn do_lrem(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some(count) = str2int(&cmd[2]) else {
+            self.out_err(out, ErrorCode::RES_ARG, "expect int");
+            return;
+        };
+        let mut map = G_MAP.lock().unwrap();
+        let list = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_int(out, 0);
+                return;
+            }
+            Some(Value::List(list)) => list,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return;
+            }
+        };
+        // a negative count removes from the tail, zero removes all
+        let limit = match count.unsigned_abs() {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+        let removed = list.remove_value(&cmd[3], limit, count < 0);
+        let emptied = list.len() == 0;
+        if emptied {
+            map.remove(&cmd[1]);
+        }
+        if removed > 0 {
+            propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+            notify::notify(notify::LIST, "lrem", &cmd[1]);
+        }
+        if emptied {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        }
+        self.out_int(out, removed as i64);
+    }
+
+    // ltrim key start stop
+    fn do_ltrim(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let (start, stop) = match (str2int(&cmd[2]), str2int(&cmd[3])) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect int");
+                return;
+            }
+        };
+        let mut map = G_MAP.lock().unwrap();
+        let list = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_ok(out);
+                return;
+            }
+            Some(Value::List(list)) => list,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return;
+            }
+        };
+        let emptied = match index_range(start, stop, list.len()) {
+            Some((start, stop)) => {
+                list.trim(start, stop);
+                false
+            }
+            None => {
+                map.remove(&cmd[1]);
+                true
+            }
+        };
+        propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        notify::notify(notify::LIST, "ltrim", &cmd[1]);
+        if emptied {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        }
+        self.out_ok(out);
+    }
+
+    // sadd key member [member ...]
+    fn do_sadd(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut map = G_MAP.lock().unwrap();
+        let set = match map.get_or_insert_with(&cmd[1], || Value::Set(Set::new())) {
+            Value::Set(set) => set,
+            _ => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect set");
+                return;
+            }
+        };
+        let added = cmd[2..].iter().filter(|member| set.add(member)).count();
+        propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        if added > 0 {
+            notify::notify(notify::SET, "sadd", &cmd[1]);
+        }
+        self.out_int(out, added as i64);
+    }
+
+    // srem key member [member ...]
+    fn do_srem(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut map = G_MAP.lock().unwrap();
+        let set = match map.get_mut(&cmd[1]) {
+            None => {
+                self.out_int(out, 0);
+                return;
+            }
+            Some(Value::Set(set)) => set,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect set");
+                return;
+            }
+        };
+        let removed = cmd[2..].iter().filter(|member| set.remove(member)).count();
+        let emptied = set.len() == 0;
+        if emptied {
+            map.remove(&cmd[1]);
+        }
+        if removed > 0 {
+            propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+            notify::notify(notify::SET, "srem", &cmd[1]);
+        }
+        if emptied {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        }
+        self.out_int(out, removed as i64);
+    }
+
+    // sismember key member, smismember key member [member ...]
+    fn do_sismember(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, multi: bool) {
+        let map = G_MAP.lock().unwrap();
+        let set = match map.get(&cmd[1]) {
+            None => None,
+            Some(Value::Set(set)) => Some(set),
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect set");
+                return;
+            }
+        };
+        let found = |member: &[u8]| set.is_some_and(|set| set.contains(member)) as i64;
+        if !multi {
+            return self.out_int(out, found(&cmd[2]));
+        }
+        self.out_arr(out, cmd.len() - 2);
+        for member in &cmd[2..] {
+            self.out_int(out, found(member));
+        }
+    }
+
+    // scard key
+    fn do_scard(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        match map.get(&cmd[1]) {
+            None => self.out_int(out, 0),
+            Some(Value::Set(set)) => self.out_int(out, set.len() as i64),
+            Some(_) => self.out_err(out, ErrorCode::RES_TYPE, "expect set"),
+        }
+    }
+
+    // spop key [count]
+    fn do_spop(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let count = match cmd.get(2).map(|c| str2int(c)) {
+            None => None,
+            Some(Some(c)) if c >= 0 => Some(c as usize),
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect non-negative int");
+                return;
+            }
+        };
+
+        let mut map = G_MAP.lock().unwrap();
+        let set = match map.get_mut(&cmd[1]) {
+            None if count.is_some() => return self.out_arr(out, 0),
+            None => return self.out_nil(out),
+            Some(Value::Set(set)) => set,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect set");
+                return;
+            }
+        };
+        let mut popped = Vec::new();
+        while popped.len() < count.unwrap_or(1) {
+            let Some(member) = set.random() else {
+                break;
+            };
+            set.remove(&member);
+            popped.push(member);
+        }
+        let emptied = set.len() == 0;
+        if emptied {
+            map.remove(&cmd[1]);
+        }
+        // the members are random, so what was removed is logged instead
+        if !popped.is_empty() {
+            let mut logged: Vec<&[u8]> = vec![b"srem", &cmd[1]];
+            logged.extend(popped.iter().map(Vec::as_slice));
+            propagate(&logged);
+            notify::notify(notify::SET, "spop", &cmd[1]);
+        }
+        if emptied {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        }
+
+        if count.is_some() {
+            self.out_arr(out, popped.len());
+        }
+        for member in &popped {
+            self.out_str(out, member);
+        }
+    }
+
+    // srandmember key [count], a negative count may repeat members
+    fn do_srandmember(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let count = match cmd.get(2).map(|c| str2int(c)) {
+            None => None,
+            Some(Some(c)) => Some(c),
+            Some(None) => {
+                self.out_err(out, ErrorCode::RES_ARG, "expect int");
+                return;
+            }
+        };
+
+        let map = G_MAP.lock().unwrap();
+        let set = match map.get(&cmd[1]) {
+            None if count.is_some() => return self.out_arr(out, 0),
+            None => return self.out_nil(out),
+            Some(Value::Set(set)) => set,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect set");
+                return;
+            }
+        };
+        if let Some(c) = count.filter(|c| *c < 0) {
+            // members may repeat, so the reply is as long as asked for; it
+            // is written as it is picked and may be no larger than a request
+            let max_msg = G_CONFIG.read().unwrap().max_msg;
+            let too_large = "count too large for a reply";
+            if c.unsigned_abs() > max_msg as u64 {
+                self.out_err(out, ErrorCode::RES_ARG, too_large);
+                return;
+            }
+            let start = out.len();
+            self.out_arr(out, c.unsigned_abs() as usize);
+            for member in (0..c.unsigned_abs()).filter_map(|_| set.random()) {
+                self.out_str(out, &member);
+                if out.len() - start > max_msg {
+                    out.truncate(start);
+                    self.out_err(out, ErrorCode::RES_ARG, too_large);
+                    return;
+                }
+            }
+            return;
+        }
+        let members = match count {
+            None => set.random().into_iter().collect(),
+            Some(c) if c as usize >= set.len() => set.members(),
+            Some(c) => {
+                // distinct members: shuffle the first `c` into place
+                let mut members = set.members();
+                for i in 0..c as usize {
+                    let j = i + set::rand_u64() as usize % (members.len() - i);
+                    members.swap(i, j);
+                }
+                members.truncate(c as usize);
+                members
+            }
+        };
+
+        if count.is_some() {
+            self.out_arr(out, members.len());
+        }
+        for member in &members {
+            self.out_str(out, member);
+        }
+    }
+
+    // smembers key, sinter|sunion|sdiff key [key ...],
+    // sinterstore|sunionstore|sdiffstore dst key [key ...]
+    fn do_setop(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, op: SetOp, store: bool) {
+        let keys = if store { &cmd[2..] } else { &cmd[1..] };
+        let mut map = G_MAP.lock().unwrap();
+        let Some(result) = combine_sets(&map, keys, op) else {
+            self.out_err(out, ErrorCode::RES_TYPE, "expect set");
+            return;
+        };
+        if !store {
+            let members = result.members();
+            self.out_arr(out, members.len());
+            for member in &members {
+                self.out_str(out, member);
+            }
+            return;
+        }
+
+        let len = result.len();
+        if len == 0 {
+            map.remove(&cmd[1]);
+        } else {
+            map.insert(&cmd[1], Value::Set(result));
+        }
+        propagate(&cmd.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        if len == 0 {
+            notify::notify(notify::GENERIC, "del", &cmd[1]);
+        } else {
+            let event = match op {
+                SetOp::Inter => "sinterstore",
+                SetOp::Union => "sunionstore",
+                SetOp::Diff => "sdiffstore",
+            };
+            notify::notify(notify::SET, event, &cmd[1]);
+        }
+        self.out_int(out, len as i64);
+    }
+
+    // sscan key cursor [match pattern] [count count]
+    fn do_sscan(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some((mut cursor, pattern, count)) = self.scan_args(&cmd[2..], out) else {
+            return;
+        };
+
+        let map = G_MAP.lock().unwrap();
+        let mut members: Vec<Vec<u8>> = Vec::new();
+        match map.get(&cmd[1]) {
+            None => cursor = 0,
+            Some(Value::Set(set)) => {
+                let mut nseen = 0;
+                loop {
+                    cursor = set.scan(cursor, |member| {
+                        nseen += 1;
+                        if pattern.is_none_or(|p| glob::matches(p, member)) {
+                            members.push(member.to_vec());
+                        }
+                    });
+                    if cursor == 0 || nseen >= count {
+                        break;
+                    }
+                }
+            }
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect set");
+                return;
+            }
+        }
+
+        self.out_arr(out, 2);
+        self.out_str(out, cursor.to_string().as_bytes());
+        self.out_arr(out, members.len());
+        for member in &members {
+            self.out_str(out, member);
+        }
+    }
+
+    fn is_subscriber(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+
+    /// The header of a pub/sub frame, a push in RESP3.
+    fn out_push(&mut self, out: &mut Vec<u8>, len: usize) {
+        if self.is_resp() {
+            return resp::put_push(out, len, self.proto == Proto::Resp3);
+        }
+        self.out_arr(out, len);
+    }
+
+    /// Queues a `kind name count` confirmation of a (un)subscription as a
+    /// frame of its own.
+    fn queue_confirmation(&mut self, kind: &[u8], name: Option<&[u8]>) {
+        let mut frame = Vec::new();
+        self.out_push(&mut frame, 3);
+        self.out_str(&mut frame, kind);
+        match name {
+            Some(name) => self.out_str(&mut frame, name),
+            None => self.out_nil(&mut frame),
+        }
+        let count = self.channels.len() + self.patterns.len();
+        self.out_int(&mut frame, count as i64);
+        self.queue_reply(&frame);
+    }
+
+    // subscribe channel [channel ...], psubscribe pattern [pattern ...]
+    fn do_subscribe(&mut self, cmd: &[Vec<u8>], _out: &mut Vec<u8>, pattern: bool) {
+        for name in &cmd[1..] {
+            let mut pubsub = G_PUBSUB.lock().unwrap();
+            if pattern {
+                pubsub.psubscribe(self.token, name);
+                self.patterns.insert(name.clone());
+            } else {
+                pubsub.subscribe(self.token, name);
+                self.channels.insert(name.clone());
+            }
+            drop(pubsub);
+            let kind: &[u8] = if pattern { b"psubscribe" } else { b"subscribe" };
+            self.queue_confirmation(kind, Some(name));
+        }
+    }
+
+    // unsubscribe [channel ...], punsubscribe [pattern ...], all of them
+    // without arguments
+    fn do_unsubscribe(&mut self, cmd: &[Vec<u8>], _out: &mut Vec<u8>, pattern: bool) {
+        let kind: &[u8] = if pattern {
+            b"punsubscribe"
+        } else {
+            b"unsubscribe"
+        };
+        let subs = if pattern {
+            &self.patterns
+        } else {
+            &self.channels
+        };
+        let names: Vec<Vec<u8>> = if cmd.len() > 1 {
+            cmd[1..].to_vec()
+        } else {
+            subs.iter().cloned().collect()
+        };
+        if names.is_empty() {
+            self.queue_confirmation(kind, None);
+            return;
+        }
+        for name in &names {
+            let mut pubsub = G_PUBSUB.lock().unwrap();
+            if pattern {
+                pubsub.punsubscribe(self.token, name);
+                self.patterns.remove(name);
+            } else {
+                pubsub.unsubscribe(self.token, name);
+                self.channels.remove(name);
+            }
+            drop(pubsub);
+            self.queue_confirmation(kind, Some(name));
+        }
+    }
+
+    // publish channel message
+    fn do_publish(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let n = G_PUBSUB.lock().unwrap().publish(&cmd[1], &cmd[2]);
+        self.out_int(out, n as i64);
+    }
+
+    // pubsub channels [pattern], pubsub numsub [channel ...], pubsub numpat
+    fn do_pubsub(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let pubsub = G_PUBSUB.lock().unwrap();
+        if cmd.len() <= 3 && cmd_is(&cmd[1], "channels") {
+            let channels = pubsub.active_channels(cmd.get(2).map(Vec::as_slice));
+            self.out_arr(out, channels.len());
+            for channel in channels {
+                self.out_str(out, channel);
+            }
+        } else if cmd_is(&cmd[1], "numsub") {
+            self.out_map(out, cmd.len() - 2);
+            for channel in &cmd[2..] {
+                self.out_str(out, channel);
+                self.out_int(out, pubsub.numsub(channel) as i64);
+            }
+        } else if cmd.len() == 2 && cmd_is(&cmd[1], "numpat") {
+            self.out_int(out, pubsub.numpat() as i64);
+        } else {
+            self.out_err(out, ErrorCode::RES_ERR, "unknown PUBSUB subcommand");
+        }
+    }
+
+    /// Queues a published message and starts writing it out.
+    fn deliver(&mut self, d: &Delivery) {
+        let mut frame = Vec::new();
+        match &d.pattern {
+            Some(pattern) => {
+                self.out_push(&mut frame, 4);
+                self.out_str(&mut frame, b"pmessage");
+                self.out_str(&mut frame, pattern);
+            }
+            None => {
+                self.out_push(&mut frame, 3);
+                self.out_str(&mut frame, b"message");
+            }
+        }
+        self.out_str(&mut frame, &d.channel);
+        self.out_str(&mut frame, &d.message);
+        self.queue_reply(&frame);
+        if self.state == State::Reading {
+            self.state = State::Writing;
+        }
+        if self.state != State::Closed {
+            self.state_res();
+        }
+    }
+
+    // replicaof host port, replicaof no one
+    fn do_replicaof(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if cmd_is(&cmd[1], "no") && cmd_is(&cmd[2], "one") {
+            G_REPL.lock().unwrap().become_primary();
+            return self.out_ok(out);
+        }
+        let Some(port) = str2int(&cmd[2]).and_then(|port| u16::try_from(port).ok()) else {
+            self.out_err(out, ErrorCode::RES_ARG, "invalid port");
+            return;
+        };
+        let host = String::from_utf8_lossy(&cmd[1]).into_owned();
+        // the event loop connects to it
+        G_REPL.lock().unwrap().follow(host, port);
+        self.out_ok(out);
+    }
+
+    // psync replid offset, sent by a replica to start replicating
+    fn do_psync(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let map = G_MAP.lock().unwrap();
+        let mut repl = G_REPL.lock().unwrap();
+        if repl.is_replica() {
+            self.out_err(out, ErrorCode::RES_ERR, "a replica cannot have replicas");
+            return;
+        }
+        repl.start_backlog(G_CONFIG.read().unwrap().repl_backlog_size);
+        // the replica continues from its offset if we still have the
+        // writes since, otherwise it gets a snapshot first
+        let offset = str2int(&cmd[2]).and_then(|offset| u64::try_from(offset).ok());
+        let tail = match offset {
+            Some(offset) if cmd[1] == repl.replid.as_bytes() => repl.since(offset),
+            _ => None,
+        };
+        let end = repl.offset();
+        let req = match tail {
+            Some(tail) => {
+                log!(
+                    Notice,
+                    "partial resync of a replica, {} bytes behind",
+                    tail.len()
+                );
+                let mut req = encode_req(&[b"continue", repl.replid.as_bytes()]);
+                req.extend(tail);
+                req
+            }
+            None => {
+                log!(Notice, "full resync of a replica");
+                // the snapshot follows in `snapchunk` records, see
+                // `send_snapshot`
+                let data = snapshot::encode(&map, 0);
+                let (end, size) = (end.to_string(), data.len().to_string());
+                self.snapshot_out = Some((data, 0));
+                let replid = repl.replid.as_bytes();
+                encode_req(&[b"fullresync", replid, end.as_bytes(), size.as_bytes()])
+            }
+        };
+        self.replica_offset = Some(end);
+        repl.replicas.insert(self.token);
+        drop(repl);
+        drop(map);
+        self.send_stream(&req);
+        self.send_snapshot();
+    }
+
+    /// Queues more of the snapshot of a full resync, a chunk at a time while
+    /// less than a chunk is waiting to be sent, so neither a record nor the
+    /// output buffer ever holds the whole snapshot.
+    fn send_snapshot(&mut self) {
+        while self.state != State::Closed && self.wbuf.len() - self.wbuf_sent < K_SNAPSHOT_CHUNK {
+            let Some((data, sent)) = self.snapshot_out.as_mut() else {
+                return;
+            };
+            let end = (*sent + K_SNAPSHOT_CHUNK).min(data.len());
+            let req = encode_req(&[b"snapchunk", &data[*sent..end]]);
+            *sent = end;
+            if end == data.len() {
+                self.snapshot_out = None;
+            }
+            // what was sent already is dropped, not to grow the buffer
+            self.wbuf.drain(..self.wbuf_sent);
+            self.wbuf_sent = 0;
+            self.send_stream(&req);
+        }
+    }
 
+    // fullresync replid offset size, the first record a replica gets from
+    // its primary when it cannot continue where it was; the snapshot
+    // follows in `snapchunk` records
+    fn do_fullresync(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let offset = str2int(&cmd[2]).and_then(|offset| u64::try_from(offset).ok());
+        let size = str2int(&cmd[3]).and_then(|size| usize::try_from(size).ok());
+        let (true, Some(offset), Some(size)) = (self.link, offset, size) else {
+            self.out_err(out, ErrorCode::RES_ERR, "Unknown CMD");
+            return;
+        };
+        self.resync = Some(Resync {
+            replid: String::from_utf8_lossy(&cmd[1]).into_owned(),
+            offset,
+            size,
+            data: Vec::new(),
+        });
+        self.receive_snapshot(&[]);
+    }
 
+    // snapchunk data, a piece of the snapshot of a full resync
+    fn do_snapchunk(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if !self.link || self.resync.is_none() {
+            self.out_err(out, ErrorCode::RES_ERR, "Unknown CMD");
+            return;
+        }
+        self.receive_snapshot(&cmd[1]);
+    }
 
+    /// Adds `chunk` to the snapshot being received, and loads it once it is
+    /// complete.
+    fn receive_snapshot(&mut self, chunk: &[u8]) {
+        let Some(resync) = self.resync.as_mut() else {
+            return;
+        };
+        resync.data.extend(chunk);
+        if resync.data.len() < resync.size {
+            return;
+        }
+        let resync = self.resync.take().unwrap();
+        let mut map = Keyspace::new();
+        let loaded = if resync.data.len() > resync.size {
+            Err(Error::other("longer than announced"))
+        } else {
+            snapshot::decode(&resync.data, &mut map)
+        };
+        if let Err(e) = loaded {
+            log!(Warning, "bad snapshot from the primary: {e}");
+            self.state = State::Closed;
+            return;
+        }
+        G_MAP.lock().unwrap().replace(map);
+        let mut repl = G_REPL.lock().unwrap();
+        repl.primary_replid = resync.replid;
+        repl.primary_offset = resync.offset;
+        repl.link_synced = true;
+        drop(repl);
+        log!(Notice, "full resync with the primary done");
 
+        // the append-only file has the old data, a snapshot of the new on
+        // top of it is what a restart loads
+        if snapshot::BGSAVE_RUNNING.load(Ordering::Acquire) {
+            log!(
+                Warning,
+                "background save in progress, not saving the synced data"
+            );
+            return;
+        }
+        let path = G_CONFIG.read().unwrap().snapshot_path.clone();
+        if let Err(e) = snapshot::write(&path, &encode_snapshot()) {
+            log!(Warning, "saving the synced data failed: {e}");
+        }
+    }
 
+    // continue replid, the first record a replica gets from its primary
+    // when it continues where it was
+    fn do_continue(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if !self.link {
+            self.out_err(out, ErrorCode::RES_ERR, "Unknown CMD");
+            return;
+        }
+        G_REPL.lock().unwrap().link_synced = true;
+        log!(Notice, "partial resync with the primary");
+    }
 
+    // role
+    fn do_role(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let repl = G_REPL.lock().unwrap();
+        match &repl.primary {
+            None => {
+                self.out_arr(out, 3);
+                self.out_str(out, b"master");
+                self.out_int(out, repl.offset() as i64);
+                self.out_int(out, repl.replicas.len() as i64);
+            }
+            Some((host, port)) => {
+                self.out_arr(out, 5);
+                self.out_str(out, b"slave");
+                self.out_str(out, host.as_bytes());
+                self.out_int(out, *port as i64);
+                let state = if repl.link_synced {
+                    "connected"
+                } else {
+                    "connect"
+                };
+                self.out_str(out, state.as_bytes());
+                self.out_int(out, repl.primary_offset as i64);
+            }
+        }
+    }
 
+    // acl setuser name [rule ...] | acl getuser name | acl deluser name
+    // [name ...] | acl list | acl whoami | acl load | acl save
+    fn do_acl(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let path = G_CONFIG.read().unwrap().aclfile.clone();
+        let mut acl = G_ACL.lock().unwrap();
+        let args: Vec<String> = cmd[2..]
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        if !args.is_empty() && cmd_is(&cmd[1], "setuser") {
+            let rules: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+            if let Err(e) = acl.set_user(&args[0], &rules) {
+                self.out_err(
+                    out,
+                    ErrorCode::RES_ERR,
+                    &format!("Error in ACL SETUSER: {e}"),
+                );
+                return;
+            }
+            self.out_ok(out);
+        } else if args.len() == 1 && cmd_is(&cmd[1], "getuser") {
+            let Some(user) = acl.user(&args[0]) else {
+                self.out_nil(out);
+                return;
+            };
+            self.out_map(out, 4);
+            self.out_str(out, b"flags");
+            self.out_arr(out, user.flags().len());
+            for flag in user.flags() {
+                self.out_str(out, flag.as_bytes());
+            }
+            self.out_str(out, b"passwords");
+            self.out_arr(out, user.passwords().len());
+            for password in user.passwords() {
+                self.out_str(out, password.as_bytes());
+            }
+            self.out_str(out, b"commands");
+            self.out_str(out, user.commands().as_bytes());
+            self.out_str(out, b"keys");
+            self.out_arr(out, user.keys().len());
+            for pattern in user.keys() {
+                self.out_str(out, pattern);
+            }
+        } else if !args.is_empty() && cmd_is(&cmd[1], "deluser") {
+            if args.iter().any(|name| name == "default") {
+                self.out_err(
+                    out,
+                    ErrorCode::RES_ERR,
+                    "The 'default' user cannot be removed",
+                );
+                return;
+            }
+            let removed = args.iter().filter(|name| acl.del_user(name)).count();
+            self.out_int(out, removed as i64);
+        } else if cmd.len() == 2 && cmd_is(&cmd[1], "list") {
+            let users = acl.list();
+            self.out_arr(out, users.len());
+            for user in users {
+                self.out_str(out, user.as_bytes());
+            }
+        } else if cmd.len() == 2 && cmd_is(&cmd[1], "whoami") {
+            let name = self.user.clone().unwrap_or_default();
+            self.out_str(out, name.as_bytes());
+        } else if cmd.len() == 2 && cmd_is(&cmd[1], "load") {
+            if path.is_empty() {
+                self.out_err(out, ErrorCode::RES_ERR, "no aclfile configured");
+                return;
+            }
+            match Acl::load(&path) {
+                Ok(loaded) => {
+                    *acl = loaded;
+                    self.out_ok(out);
+                }
+                Err(e) => self.out_err(out, ErrorCode::RES_ERR, &e),
+            }
+        } else if cmd.len() == 2 && cmd_is(&cmd[1], "save") {
+            if path.is_empty() {
+                self.out_err(out, ErrorCode::RES_ERR, "no aclfile configured");
+                return;
+            }
+            match acl.save(&path) {
+                Ok(()) => self.out_ok(out),
+                Err(e) => self.out_err(out, ErrorCode::RES_ERR, &e.to_string()),
+            }
+        } else {
+            self.out_err(out, ErrorCode::RES_ERR, "unknown ACL subcommand");
+        }
+    }
 
+    // config get pattern [pattern ...]
+    fn do_config_get(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let config = G_CONFIG.read().unwrap();
+        let names: Vec<&str> = config::NAMES
+            .into_iter()
+            .filter(|name| {
+                cmd[2..]
+                    .iter()
+                    .any(|pattern| glob::matches(&pattern.to_ascii_lowercase(), name.as_bytes()))
+            })
+            .collect();
+        self.out_map(out, names.len());
+        for name in names {
+            self.out_str(out, name.as_bytes());
+            self.out_str(out, config.get(name).unwrap().as_bytes());
+        }
+    }
 
+    // config set name value [name value ...], all or none of them
+    fn do_config_set(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let mut config = G_CONFIG.read().unwrap().clone();
+        for pair in cmd[2..].chunks(2) {
+            let name = String::from_utf8_lossy(&pair[0]).to_lowercase();
+            let value = String::from_utf8_lossy(&pair[1]);
+            let result = if config::IMMUTABLE.contains(&name.as_str()) {
+                Err(format!("{name} can only be set at startup"))
+            } else {
+                config.set(&name, &value)
+            };
+            if let Err(e) = result {
+                self.out_err(out, ErrorCode::RES_ERR, &format!("CONFIG SET failed: {e}"));
+                return;
+            }
+        }
+        config.apply();
+        *G_CONFIG.write().unwrap() = config;
+        self.out_ok(out);
+    }
 
+    // config rewrite
+    fn do_config_rewrite(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let config = G_CONFIG.read().unwrap().clone();
+        if let Err(e) = config.rewrite() {
+            self.out_err(
+                out,
+                ErrorCode::RES_ERR,
+                &format!("CONFIG REWRITE failed: {e}"),
+            );
+            return;
+        }
+        self.out_ok(out);
+    }
 
+    // info [section ...]
+    fn do_info(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let sections: Vec<String> = cmd[1..]
+            .iter()
+            .map(|section| String::from_utf8_lossy(section).to_lowercase())
+            .collect();
+        self.out_str(out, info::info(&sections).as_bytes());
+    }
 
+    // memory stats
+    fn do_memory_stats(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let evicted_keys = G_MAP.lock().unwrap().evicted_keys();
+        let (maxmemory, policy) = {
+            let config = G_CONFIG.read().unwrap();
+            (config.maxmemory, config.maxmemory_policy)
+        };
+        self.out_map(out, 4);
+        self.out_str(out, b"used_memory");
+        self.out_int(out, used_memory() as i64);
+        self.out_str(out, b"maxmemory");
+        self.out_int(out, maxmemory as i64);
+        self.out_str(out, b"maxmemory_policy");
+        self.out_str(out, policy.name().as_bytes());
+        self.out_str(out, b"evicted_keys");
+        self.out_int(out, evicted_keys as i64);
+    }
 
+    // multi
+    fn do_multi(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if self.multi.is_some() {
+            self.out_err(out, ErrorCode::RES_ERR, "MULTI calls can not be nested");
+            return;
+        }
+        self.multi = Some(Vec::new());
+        self.multi_failed = false;
+        self.out_ok(out);
+    }
 
+    /// Queues a command between MULTI and EXEC. Unknown commands and wrong
+    /// numbers of arguments are caught here, so that EXEC runs either all
+    /// of the commands or none.
+    fn queue_command(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        // these reply on their own or would snapshot half a transaction
+        let not_allowed = [
+            "subscribe",
+            "psubscribe",
+            "unsubscribe",
+            "punsubscribe",
+            "save",
+            "bgsave",
+        ];
+        let msg = if not_allowed.iter().any(|name| cmd_is(&cmd[0], name)) {
+            "command not allowed inside a transaction"
+        } else if Self::route(cmd).is_none() {
+            "Unknown CMD"
+        } else {
+            self.multi.as_mut().unwrap().push(cmd.to_vec());
+            self.out_status(out, "QUEUED");
+            return;
+        };
+        self.multi_failed = true;
+        self.out_err(out, ErrorCode::RES_ERR, msg);
+    }
 
-    fn parse_req(&mut self, reqlen: usize, cmd: &mut Vec<String>) -> std::io::Result<()> {
-        if reqlen < 8 {
-            return Err(Error::new(io::ErrorKind::Other, "Bad request!"));
+    // exec
+    fn do_exec(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some(queued) = self.multi.take() else {
+            self.out_err(out, ErrorCode::RES_ERR, "EXEC without MULTI");
+            return;
+        };
+        let dirty = G_MAP.lock().unwrap().is_dirty(self.token);
+        self.unwatch_all();
+        if self.multi_failed {
+            let msg = "Transaction discarded because of previous errors.";
+            self.out_err(out, ErrorCode::RES_ABORT, msg);
+            return;
+        }
+        // a watched key was modified
+        if dirty {
+            self.out_nil(out);
+            return;
         }
 
-        // Extract the number of commands from bytes 4 to 8
-        let mut zN = [0_u8; 4];
-        zN.copy_from_slice(&self.rbuf[4..8]);
-        let mut n = u32::from_le_bytes(zN);
+        // the event loop runs nothing else in the meantime, which makes the
+        // transaction atomic
+        self.out_arr(out, queued.len());
+        propagate_begin();
+        self.in_exec = true;
+        for cmd in &queued {
+            let handler = Self::route(cmd).expect("checked when queued");
+            handler(self, cmd, out);
+        }
+        self.in_exec = false;
+        propagate_commit();
+    }
 
-        println!("{} number of strings", n);
-        if n > K_MAX_ARGS as u32 {
-            return Err(Error::new(io::ErrorKind::Other, "n > K_MAX_ARGS"));
+    // discard
+    fn do_discard(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if self.multi.take().is_none() {
+            self.out_err(out, ErrorCode::RES_ERR, "DISCARD without MULTI");
+            return;
         }
+        self.unwatch_all();
+        self.out_ok(out);
+    }
 
-        let mut pos = 8_usize; // Start reading commands from position 8
-        while n > 0 {
-            n -= 1;
-            println!("{pos}");
-            // Check if there are enough bytes to read the length of the command
-            if pos > reqlen {
-                return Err(Error::new(io::ErrorKind::Other, "string not expected"));
+    // watch key [key ...]
+    fn do_watch(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        if self.multi.is_some() {
+            self.out_err(out, ErrorCode::RES_ERR, "WATCH inside MULTI is not allowed");
+            return;
+        }
+        let mut map = G_MAP.lock().unwrap();
+        for key in &cmd[1..] {
+            if !self.watched.contains(key) {
+                map.watch(self.token, key);
+                self.watched.push(key.clone());
             }
+        }
+        drop(map);
+        self.out_ok(out);
+    }
 
-            let mut zs = [0_u8; 4];
-            zs.copy_from_slice(&self.rbuf[pos..pos + 4]);
-            let sz = u32::from_le_bytes(zs);
-            println!("sz is {sz}");
-
-            // Check if there are enough bytes to read the command content
-            if pos + (sz as usize) > reqlen {
-                return Err(Error::new(io::ErrorKind::Other, "too less information"));
-            }
+    // unwatch
+    fn do_unwatch(&mut self, _cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        self.unwatch_all();
+        self.out_ok(out);
+    }
 
-            let message = String::from_utf8_lossy(&self.rbuf[pos + 4..pos + 4 + (sz as usize)]);
-            cmd.push(message.to_string());
-            println!("the command is {:?}", cmd);
+    fn unwatch_all(&mut self) {
+        let watched = std::mem::take(&mut self.watched);
+        G_MAP.lock().unwrap().unwatch(self.token, &watched);
+    }
 
-            pos += 4 + (sz as usize);
+    /// Pops an element from the first non-empty list of `keys` and replies
+    /// with the key and the element. Returns `false` if all are empty.
+    fn try_pop(&mut self, keys: &[Vec<u8>], front: bool, out: &mut Vec<u8>) -> bool {
+        let mut map = G_MAP.lock().unwrap();
+        for key in keys {
+            let list = match map.get_mut(key) {
+                None => continue,
+                Some(Value::List(list)) => list,
+                Some(_) => {
+                    self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                    return true;
+                }
+            };
+            let val = if front {
+                list.pop_front()
+            } else {
+                list.pop_back()
+            };
+            let val = val.unwrap();
+            let emptied = list.len() == 0;
+            if emptied {
+                map.remove(key);
+            }
+            propagate(&[if front { b"lpop" } else { b"rpop" }, key]);
+            notify::notify(notify::LIST, if front { "lpop" } else { "rpop" }, key);
+            if emptied {
+                notify::notify(notify::GENERIC, "del", key);
+            }
+            self.out_arr(out, 2);
+            self.out_str(out, key);
+            self.out_str(out, &val);
+            return true;
         }
+        false
+    }
 
-        if pos != reqlen + 4 {
-            return Err(Error::new(io::ErrorKind::InvalidData, "Garbage trailing!"));
+    /// Moves an element from one end of `src` to one end of `dst` and
+    /// replies with it. Returns `false` if `src` is empty.
+    fn try_move(
+        &mut self,
+        src: &[u8],
+        dst: &[u8],
+        from_front: bool,
+        to_front: bool,
+        out: &mut Vec<u8>,
+    ) -> bool {
+        let mut map = G_MAP.lock().unwrap();
+        if !matches!(map.get(dst), None | Some(Value::List(_))) {
+            self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+            return true;
         }
-
-        Ok(())
+        let list = match map.get_mut(src) {
+            None => return false,
+            Some(Value::List(list)) => list,
+            Some(_) => {
+                self.out_err(out, ErrorCode::RES_TYPE, "expect list");
+                return true;
+            }
+        };
+        let val = if from_front {
+            list.pop_front()
+        } else {
+            list.pop_back()
+        };
+        let val = val.unwrap();
+        let emptied = list.len() == 0;
+        if emptied {
+            map.remove(src);
+        }
+        let Value::List(list) = map.get_or_insert_with(dst, || Value::List(List::new())) else {
+            unreachable!("checked above");
+        };
+        if to_front {
+            list.push_front(val.clone());
+        } else {
+            list.push_back(val.clone());
+        }
+        let side = |front: bool| if front { &b"left"[..] } else { &b"right"[..] };
+        propagate(&[b"lmove", src, dst, side(from_front), side(to_front)]);
+        notify::notify(notify::LIST, if from_front { "lpop" } else { "rpop" }, src);
+        if emptied {
+            notify::notify(notify::GENERIC, "del", src);
+        }
+        notify::notify(notify::LIST, if to_front { "lpush" } else { "rpush" }, dst);
+        blocking::signal_ready(dst);
+        self.out_str(out, &val);
+        true
     }
-    fn do_get(&mut self, cmd: &Vec<String>, out: &mut Vec<u8>)  {
-        let map = G_MAP.lock().unwrap();
 
-        
-        let val = map.get(&cmd[1]);
-        if val.is_none() {
-            self.out_nil(out);
+    // blpop key [key ...] timeout, brpop key [key ...] timeout
+    fn do_blpop(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>, front: bool) {
+        let Some(deadline) = self.block_deadline(&cmd[cmd.len() - 1], out) else {
+            return;
+        };
+        let keys = &cmd[1..cmd.len() - 1];
+        if self.try_pop(keys, front, out) {
             return;
         }
-        let val = val.unwrap();
-        self.out_str(out,val);
-        return;
+        self.block(out, keys.to_vec(), deadline, front, None);
     }
 
-    fn do_set(&mut self, cmd: &Vec<String>, out: &mut Vec<u8>)  {
-        let mut map = G_MAP.lock().unwrap();
-        map.insert(cmd[1].clone(), cmd[2].clone());
-        self.out_nil(out);
-        return;
+    // lmove src dst left|right left|right, blmove ... timeout
+    fn do_lmove(&mut self, cmd: &[Vec<u8>], out: &mut Vec<u8>) {
+        let side = |s: &[u8]| {
+            if cmd_is(s, "left") {
+                Some(true)
+            } else if cmd_is(s, "right") {
+                Some(false)
+            } else {
+                None
+            }
+        };
+        let (Some(from_front), Some(to_front)) = (side(&cmd[3]), side(&cmd[4])) else {
+            self.out_err(out, ErrorCode::RES_ARG, "syntax error");
+            return;
+        };
+        let deadline = match cmd.get(5) {
+            None => None,
+            Some(timeout) => match self.block_deadline(timeout, out) {
+                Some(deadline) => Some(deadline),
+                None => return,
+            },
+        };
+        if self.try_move(&cmd[1], &cmd[2], from_front, to_front, out) {
+            return;
+        }
+        match deadline {
+            // LMOVE does not block
+            None => self.out_nil(out),
+            Some(deadline) => {
+                let dest = Some((cmd[2].clone(), to_front));
+                self.block(out, vec![cmd[1].clone()], deadline, from_front, dest);
+            }
+        }
     }
 
-    fn do_del(&mut self, cmd: &Vec<String>, out: &mut Vec<u8>)  {
-        let mut map = G_MAP.lock().unwrap();
-        let v=map.remove(&cmd[1]);
-        if v.is_none() {
-            self.out_int(out,0);
+    /// Parses the timeout of a blocking command, in seconds with 0 meaning
+    /// forever, into a deadline. Replies with an error if it is invalid.
+    fn block_deadline(&mut self, timeout: &[u8], out: &mut Vec<u8>) -> Option<Option<Instant>> {
+        match str2dbl(timeout) {
+            Some(0.0) => Some(None),
+            Some(secs) if secs > 0.0 && secs.is_finite() => {
+                Some(Some(Instant::now() + Duration::from_secs_f64(secs)))
+            }
+            _ => {
+                self.out_err(
+                    out,
+                    ErrorCode::RES_ARG,
+                    "timeout is not a float or out of range",
+                );
+                None
+            }
         }
-        else{
-            self.out_int(out,1);
+    }
+
+    /// Parks the connection until one of `keys` gets an element. The event
+    /// loop picks it up from the `Blocked` state.
+    fn block(
+        &mut self,
+        out: &mut Vec<u8>,
+        keys: Vec<Vec<u8>>,
+        deadline: Option<Instant>,
+        front: bool,
+        dest: Option<(Vec<u8>, bool)>,
+    ) {
+        // the replayed append-only file has nobody to wait for, and a
+        // transaction cannot wait
+        if self.stream.is_none() || self.in_exec {
+            self.out_blocked_nil(out, dest.is_some());
+            return;
         }
-        return;
+        self.blocked = Some(Blocked {
+            keys,
+            deadline,
+            front,
+            dest,
+        });
+        self.state = State::Blocked;
     }
 
     fn state_res(&mut self) {
         while self.try_flush_buffer() {}
     }
     fn try_flush_buffer(&mut self) -> bool {
-        assert!(self.rbuf_size < self.rbuf.len());
         match self.write() {
             std::io::Result::Ok(n) => {
                 self.wbuf_sent += n;
-                assert!(self.wbuf_sent <= self.wbuf_size);
-                if self.wbuf_sent == self.wbuf_size {
+                assert!(self.wbuf_sent <= self.wbuf.len());
+                if self.wbuf_sent == self.wbuf.len() {
                     self.wbuf_sent = 0;
-                    self.wbuf_size = 0;
-                    self.state = State::Reading;
+                    self.wbuf.clear();
+                    self.soft_limit_since = None;
+                    if self.state == State::Writing {
+                        self.state = State::Reading;
+                    }
                     return false;
                 }
-                return true;
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                return false;
-            }
-            Err(ref e) if e.kind() == ErrorKind::Interrupted => {
-                return true;
+                true
             }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => true,
             Err(_) => {
                 self.state = State::Closed;
-                return false;
+                false
             }
         }
     }
 
     fn connection_io(&mut self) {
-        if self.state == State::Reading {
-            self.state_req();
-            println!("Socket in reading state");
-        } else if self.state == State::Writing {
+        // a client with replies still pending may send more requests, so
+        // both directions are served on every event
+        if self.state == State::Writing || self.state == State::Blocked {
             self.state_res();
-            println!("Socket in writing state");
+        }
+        if self.state != State::Closed {
+            self.state_req();
+        }
+    }
+
+    /// Leaves the blocked state with `out` as the reply of the blocking
+    /// command, then handles the requests buffered in the meantime.
+    fn unblock(&mut self, out: &[u8]) {
+        self.blocked = None;
+        self.state = State::Reading;
+        self.queue_reply(out);
+        while self.try_one_request() {}
+        self.rbuf.drain(..self.rbuf_pos);
+        self.rbuf_pos = 0;
+        if self.state != State::Closed {
+            self.state_req();
+        }
+    }
+
+    /// Retries the command a blocked connection waits on, returns `false`
+    /// if it is still blocked.
+    fn retry_blocked(&mut self) -> bool {
+        let Some(b) = self.blocked.take() else {
+            return true;
+        };
+        let mut out = Vec::new();
+        let served = match &b.dest {
+            None => self.try_pop(&b.keys, b.front, &mut out),
+            Some((dst, to_front)) => self.try_move(&b.keys[0], dst, b.front, *to_front, &mut out),
+        };
+        if !served {
+            self.blocked = Some(b);
+            return false;
+        }
+        self.unblock(&out);
+        true
+    }
+
+    /// Answers a blocked connection whose timeout has passed.
+    fn time_out_blocked(&mut self) {
+        let mut out = Vec::new();
+        let moves = self.blocked.as_ref().is_some_and(|b| b.dest.is_some());
+        self.out_blocked_nil(&mut out, moves);
+        self.unblock(&out);
+    }
+
+    /// The reply of a blocking command that got nothing: a null array for
+    /// BLPOP and BRPOP, a null string for BLMOVE, as redis answers.
+    fn out_blocked_nil(&mut self, out: &mut Vec<u8>, moves: bool) {
+        if moves {
+            self.out_nil(out);
+        } else {
+            self.out_null_arr(out);
         }
     }
 
-    fn new(stream: TcpStream) -> Self {
+    fn new(stream: Option<Stream>, token: Token) -> Self {
         Conn {
+            token,
             state: State::Reading,
             stream,
-            rbuf_size: 0,
-            rbuf: [0; 4 + K_MAX_MSG],
-            wbuf_size: 0,
-            wbuf: [0; 4 + K_MAX_MSG],
+            proto: Proto::Unknown,
+            rbuf: Vec::new(),
+            rbuf_pos: 0,
+            wbuf: Vec::new(),
             wbuf_sent: 0,
+            soft_limit_since: None,
+            blocked: None,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            multi: None,
+            multi_failed: false,
+            in_exec: false,
+            watched: Vec::new(),
+            link: false,
+            link_pending: 0,
+            link_auth: false,
+            replica_offset: None,
+            snapshot_out: None,
+            resync: None,
+            user: default_user(),
+            buffers_size: 0,
         }
     }
 
     fn read(&mut self) -> std::io::Result<usize> {
-        match self.stream.read(&mut self.rbuf[self.rbuf_size..]) {
-            Ok(n) => Ok(n),
-            Err(e) => Err(e),
-        }
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        let start = self.rbuf.len();
+        self.rbuf.resize(start + K_READ_CHUNK, 0);
+        let res = stream.read(&mut self.rbuf[start..]);
+        self.rbuf.truncate(start + *res.as_ref().unwrap_or(&0));
+        res
     }
 
     fn write(&mut self) -> std::io::Result<usize> {
-        if self.wbuf_sent < self.wbuf_size {
-            match self
-                .stream
-                .write(&self.wbuf[self.wbuf_sent..self.wbuf_size])
-            {
-                Ok(n) => Ok(n),
-                Err(e) => Err(e),
+        match self.stream.as_mut() {
+            Some(stream) if self.wbuf_sent < self.wbuf.len() => {
+                stream.write(&self.wbuf[self.wbuf_sent..])
             }
-        } else {
-            Ok(0)
+            Some(_) => Ok(0),
+            // nobody to send to, drop the output
+            None => Ok(self.wbuf.len() - self.wbuf_sent),
         }
     }
 
     fn close(&mut self) {
         self.state = State::Closed;
-        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        if let Some(stream) = self.stream.as_ref() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
     }
 }
 
 impl Drop for Conn {
     fn drop(&mut self) {
-        println!("Dropping connection and closing stream.");
+        log!(Verbose, "Dropping connection and closing stream.");
+        self.unwatch_all();
+        if self.replica_offset.is_some() {
+            G_REPL.lock().unwrap().replicas.remove(&self.token);
+        }
+        let mut pubsub = G_PUBSUB.lock().unwrap();
+        for channel in &self.channels {
+            pubsub.unsubscribe(self.token, channel);
+        }
+        for pattern in &self.patterns {
+            pubsub.punsubscribe(self.token, pattern);
+        }
+        drop(pubsub);
+        memory::resize_buffers(self.buffers_size, 0);
         self.close();
     }
 }
 #[repr(u32)]
+#[allow(non_camel_case_types, dead_code)]
 enum ErrorCode {
     RES_OK = 0,
     RES_ERR = 1,
     RES_NX = 2,
+    RES_TYPE = 3,       // The key holds a value of another type
+    RES_ARG = 4,        // A malformed argument
+    RES_ABORT = 5,      // EXEC of a transaction that had errors
+    RES_OOM = 6,        // Over the memory limit
+    RES_READONLY = 7,   // A write sent to a replica
+    RES_NOAUTH = 8,     // A command before authenticating
+    RES_NOPERM = 9,     // A command or key the user may not access
+    RES_WRONGPASS = 10, // A failed AUTH
+}
+
+/// The reply types of the binary protocol, a tag byte followed by
+/// little-endian fields. `client.rs` keeps a copy of this enum.
+#[allow(non_camel_case_types)]
+enum Serialization {
+    SER_NIL = 0, // Like `NULL`
+    SER_ERR = 1, // An error code u32 and a message
+    SER_STR = 2, // A length u32 and the bytes
+    SER_INT = 3, // A signed 64-bit integer
+    SER_ARR = 4, // A length u32, then that many replies
+    SER_DBL = 5, // An IEEE-754 double
+}
+
+/// Returns how long the event loop may sleep before the next key expires.
+fn next_timer() -> Duration {
+    let poll_timeout = G_CONFIG.read().unwrap().poll_timeout;
+    let map = G_MAP.lock().unwrap();
+    let timeout = match map.next_deadline() {
+        None => poll_timeout,
+        Some(deadline) => deadline.saturating_sub(now_ms()).min(poll_timeout),
+    };
+    Duration::from_millis(timeout)
+}
+
+/// Updates the bookkeeping of the event loop after a connection ran: drops
+/// it once closed, and queues it as a waiter once blocked.
+fn sync_conn(token: Token, connections: &mut HashMap<Token, Conn>, waiters: &mut Waiters) {
+    let Some(conn) = connections.get(&token) else {
+        return;
+    };
+    match (&conn.state, &conn.blocked) {
+        (State::Closed, _) => {
+            connections.remove(&token);
+            waiters.remove(token);
+            info::CONNECTED_CLIENTS.store(connections.len() as u64, Ordering::Relaxed);
+        }
+        (State::Blocked, Some(b)) => waiters.add(token, &b.keys, b.deadline),
+        _ => {}
+    }
 }
 
-enum Serialization{
-    SER_NIL = 0,    // Like `NULL`
-    SER_ERR = 1,    // An error code and message
-    SER_STR = 2,    // A string
-    SER_INT = 3,    // A int64
-    SER_ARR = 4,    // Array
+/// Serves the connections blocked on keys that got pushed to, longest
+/// waiting first, for as long as the keys have elements.
+fn serve_blocked(connections: &mut HashMap<Token, Conn>, waiters: &mut Waiters) {
+    loop {
+        // serving may push to other keys (BLMOVE) and run the requests the
+        // served clients had buffered, which may push too
+        let ready = blocking::take_ready();
+        if ready.is_empty() {
+            break;
+        }
+        for key in ready {
+            for token in waiters.waiting_on(&key) {
+                let Some(conn) = connections.get_mut(&token) else {
+                    continue;
+                };
+                if !conn.retry_blocked() {
+                    break;
+                }
+                waiters.remove(token);
+                sync_conn(token, connections, waiters);
+            }
+        }
+    }
+}
+
+/// Writes the messages published since the last call to their subscribers.
+fn deliver_messages(connections: &mut HashMap<Token, Conn>, waiters: &mut Waiters) {
+    let outbox = G_PUBSUB.lock().unwrap().take_outbox();
+    for d in outbox {
+        if let Some(conn) = connections.get_mut(&d.token) {
+            conn.deliver(&d);
+        }
+        sync_conn(d.token, connections, waiters);
+    }
+}
+
+/// Sends the writes made since the last call to the replicas, dropping
+/// those too far behind for the backlog to catch them up.
+fn feed_replicas(connections: &mut HashMap<Token, Conn>, waiters: &mut Waiters) {
+    let repl = G_REPL.lock().unwrap();
+    let end = repl.offset();
+    let mut fed = Vec::new();
+    for &token in &repl.replicas {
+        let Some(conn) = connections.get_mut(&token) else {
+            continue;
+        };
+        let Some(sent) = conn.replica_offset else {
+            continue;
+        };
+        // the stream waits until the snapshot of a full resync is sent
+        if conn.snapshot_out.is_some() {
+            conn.send_snapshot();
+            if conn.snapshot_out.is_some() || sent == end {
+                fed.push(token);
+                continue;
+            }
+        } else if sent == end {
+            continue;
+        }
+        match repl.since(sent) {
+            Some(data) => {
+                conn.replica_offset = Some(end);
+                conn.send_stream(&data);
+            }
+            None => {
+                log!(Warning, "replica too far behind, dropping it");
+                conn.state = State::Closed;
+            }
+        }
+        fed.push(token);
+    }
+    drop(repl);
+    for token in fed {
+        sync_conn(token, connections, waiters);
+    }
+}
+
+/// Opens the link of a replica to `host:port`, asking to continue from
+/// `offset` in the history `replid` of the primary.
+fn connect_primary(
+    host: &str,
+    port: u16,
+    replid: &str,
+    offset: u64,
+    token: Token,
+) -> io::Result<Conn> {
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::other("no address"))?;
+    let mut conn = Conn::new(Some(Stream::Tcp(TcpStream::connect(addr)?)), token);
+    conn.proto = Proto::Binary;
+    conn.link = true;
+    let (user, password) = {
+        let config = G_CONFIG.read().unwrap();
+        (config.masteruser.clone(), config.masterauth.clone())
+    };
+    if !password.is_empty() {
+        let user = if user.is_empty() { "default" } else { &user };
+        conn.wbuf = encode_req(&[b"auth", user.as_bytes(), password.as_bytes()]);
+        conn.link_auth = true;
+    }
+    conn.wbuf.extend(encode_req(&[
+        b"psync",
+        replid.as_bytes(),
+        offset.to_string().as_bytes(),
+    ]));
+    conn.state = State::Writing;
+    Ok(conn)
 }
 
+/// Brings the connections in line with the replication state: drops a
+/// link to a primary not followed anymore and the replicas of a server
+/// that became a replica, and connects to the primary, retrying once a
+/// second.
+fn replication_cron(
+    poll: &Poll,
+    connections: &mut HashMap<Token, Conn>,
+    waiters: &mut Waiters,
+    next_token: &mut Token,
+) {
+    let mut repl = G_REPL.lock().unwrap();
+    let mut closing = Vec::new();
+    if let Some((token, host, port)) = repl.link.clone() {
+        let followed = repl.primary == Some((host, port));
+        if !followed || !connections.contains_key(&token) {
+            closing.push(token);
+            repl.link = None;
+            repl.link_synced = false;
+        }
+    }
+    if repl.is_replica() {
+        closing.extend(repl.replicas.drain());
+    }
+
+    let retry = repl
+        .last_connect
+        .is_none_or(|t| t.elapsed() >= Duration::from_secs(1));
+    if let (Some((host, port)), None, true) = (repl.primary.clone(), &repl.link, retry) {
+        repl.last_connect = Some(Instant::now());
+        let token = *next_token;
+        next_token.0 += 1;
+        let conn = connect_primary(
+            &host,
+            port,
+            &repl.primary_replid,
+            repl.primary_offset,
+            token,
+        );
+        let registered = conn.and_then(|mut conn| {
+            poll.registry().register(
+                conn.stream.as_mut().unwrap(),
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            Ok(conn)
+        });
+        match registered {
+            Ok(conn) => {
+                log!(Notice, "connecting to the primary {host}:{port}");
+                connections.insert(token, conn);
+                repl.link = Some((token, host, port));
+            }
+            Err(e) => log!(
+                Warning,
+                "connecting to the primary {host}:{port} failed: {e}"
+            ),
+        }
+    }
+    drop(repl);
+
+    for token in closing {
+        if let Some(conn) = connections.get_mut(&token) {
+            conn.state = State::Closed;
+        }
+        sync_conn(token, connections, waiters);
+    }
+}
 
+/// Replies to the blocked connections whose timeout has passed.
+fn expire_blocked(connections: &mut HashMap<Token, Conn>, waiters: &mut Waiters) {
+    for token in waiters.timed_out(Instant::now()) {
+        waiters.remove(token);
+        if let Some(conn) = connections.get_mut(&token) {
+            conn.time_out_blocked();
+        }
+        sync_conn(token, connections, waiters);
+    }
+}
 
+/// Deletes expired keys, in bounded batches so one call never stalls the
+/// event loop. Leftovers are picked up on the next iteration since
+/// `next_timer` then returns a zero timeout.
+fn process_timers() {
+    snapshot::reap_background_save();
+    let mut map = G_MAP.lock().unwrap();
+    let n = map.expire_due(now_ms(), K_MAX_EXPIRE_WORK);
+    if n > 0 {
+        log!(Verbose, "expired {n} keys");
+    }
+}
 
+/// Serializes the keyspace along with the append-only file offset it
+/// corresponds to.
+fn encode_snapshot() -> Vec<u8> {
+    let map = G_MAP.lock().unwrap();
+    let aof_offset = G_AOF.lock().unwrap().as_ref().map_or(0, |aof| aof.len());
+    snapshot::encode(&map, aof_offset)
+}
 
+/// Rebuilds the keyspace from the snapshot plus the part of the append-only
+/// file written after it, then opens the file for appending. Must run
+/// before any client is served.
+fn load_data() -> std::io::Result<()> {
+    let config = G_CONFIG.read().unwrap().clone();
+    let offset = snapshot::load(&config.snapshot_path, &mut G_MAP.lock().unwrap())?;
+    if offset.is_some() {
+        log!(Notice, "loaded snapshot {}", config.snapshot_path);
+    }
 
+    // replays through a Conn without a socket, the replies go nowhere; it
+    // borrows the first listener's token which no client ever gets
+    let mut conn = Conn::new(None, Token(0));
+    conn.proto = Proto::Binary;
+    let path = &config.aof_path;
+    let nrec = aof::load(
+        path,
+        offset.unwrap_or(0),
+        config.aof_load_truncated,
+        |body| {
+            let mut cmd: Vec<Vec<u8>> = vec![];
+            parse_req(body, &mut cmd)?;
+            conn.do_request(&cmd, &mut Vec::new())
+        },
+    )?;
+    log!(Notice, "replayed {nrec} commands from {path}");
 
+    *G_AOF.lock().unwrap() = Some(Aof::open(path, config.aof_fsync)?);
+    Ok(())
+}
 
 fn main() -> std::io::Result<()> {
-    let addr = "127.0.0.1:8080".parse().unwrap();
-    let mut listener = TcpListener::bind(addr)?;
+    // `[config file] [--name value ...]`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("bad configuration: {e}");
+            std::process::exit(1);
+        }
+    };
+    info::START_MS.store(now_ms(), Ordering::Relaxed);
+    log::set_level(config.loglevel);
+    *G_CONFIG.write().unwrap() = config.clone();
+    if !config.aclfile.is_empty() {
+        match Acl::load(&config.aclfile) {
+            Ok(acl) => *G_ACL.lock().unwrap() = acl,
+            Err(e) => {
+                eprintln!("bad users file: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    load_data()?;
+    // after loading, so that replaying the log publishes nothing
+    config.apply();
 
     // Create a Poll instance
     let mut poll = Poll::new()?;
@@ -430,55 +3845,339 @@ fn main() -> std::io::Result<()> {
     // Create storage for events
     let mut events = Events::with_capacity(128);
 
-    // Register the listener with Poll
-    poll.registry()
-        .register(&mut listener, SERVER, Interest::READABLE)?;
+    // One listener per bind address plus the Unix socket, the listeners
+    // take the first tokens
+    let mut listeners = Vec::new();
+    if config.port != 0 {
+        for ip in &config.bind {
+            listeners.push(Listener::bind_tcp((*ip, config.port).into())?);
+        }
+    }
+    if !config.unixsocket.is_empty() {
+        listeners.push(Listener::bind_unix(
+            &config.unixsocket,
+            config.unixsocketperm,
+        )?);
+    }
+    for (i, listener) in listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(listener, Token(i), Interest::READABLE)?;
+        log!(Notice, "listening on {listener}");
+    }
 
     // A map of all client connections, keyed by Token
     let mut connections: HashMap<Token, Conn> = HashMap::new();
-    let mut next_token = Token(SERVER.0 + 1);
+    let mut next_token = Token(listeners.len());
+    let mut waiters = Waiters::new();
 
     loop {
-        // Poll for events with a timeout
-        poll.poll(&mut events, Some(Duration::from_millis(1000)))?;
+        // Poll for events, waking up in time for the next key to expire or
+        // blocked client to time out
+        let mut timeout = next_timer();
+        if let Some(deadline) = waiters.next_deadline() {
+            timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        poll.poll(&mut events, Some(timeout))?;
 
         for event in events.iter() {
             match event.token() {
-                SERVER => {
-                    // Accept new connections
-
-                    match listener.accept() {
-                        Ok((stream, _)) => {
-                            let token = next_token;
-                            next_token.0 += 1;
-
-                            // Create a new connection
-                            let mut conn = Conn::new(stream);
-
-                            // Register the new connection
-                            poll.registry().register(
-                                &mut conn.stream,
-                                token,
-                                Interest::READABLE | Interest::WRITABLE,
-                            )?;
-
-                            connections.insert(token, conn);
-                            println!("New connection created");
+                Token(i) if i < listeners.len() => {
+                    // Accept new connections, all of them since the event
+                    // is edge-triggered
+                    loop {
+                        match listeners[i].accept() {
+                            Ok(stream) => {
+                                let token = next_token;
+                                next_token.0 += 1;
+
+                                // Create a new connection
+                                let mut conn = Conn::new(Some(stream), token);
+
+                                // Register the new connection
+                                poll.registry().register(
+                                    conn.stream.as_mut().unwrap(),
+                                    token,
+                                    Interest::READABLE | Interest::WRITABLE,
+                                )?;
+
+                                connections.insert(token, conn);
+                                let clients = connections.len() as u64;
+                                info::CONNECTED_CLIENTS.store(clients, Ordering::Relaxed);
+                                info::TOTAL_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                                log!(Verbose, "New connection created");
+                            }
+                            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(e),
                         }
-                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                        Err(e) => return Err(e),
                     }
                 }
                 token => {
                     // Handle client connections
                     if let Some(conn) = connections.get_mut(&token) {
                         conn.connection_io();
-                        if matches!(conn.state, State::Closed) {
-                            connections.remove(&token);
-                        }
                     }
+                    sync_conn(token, &mut connections, &mut waiters);
+                    serve_blocked(&mut connections, &mut waiters);
+                    deliver_messages(&mut connections, &mut waiters);
+                    feed_replicas(&mut connections, &mut waiters);
                 }
             }
         }
+
+        expire_blocked(&mut connections, &mut waiters);
+        process_timers();
+        // expired keys publish notifications too
+        deliver_messages(&mut connections, &mut waiters);
+        feed_replicas(&mut connections, &mut waiters);
+        replication_cron(&poll, &mut connections, &mut waiters, &mut next_token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    // the tests run in parallel and share the server state, each holds
+    // this so that those changing the config, the users or the role of the
+    // server do not disturb the others
+    static GLOBALS: Mutex<()> = Mutex::new(());
+
+    fn globals() -> MutexGuard<'static, ()> {
+        GLOBALS.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A RESP2 connection without a socket, so the ACL and memory checks
+    /// do not apply to it.
+    fn conn() -> Conn {
+        let mut conn = Conn::new(None, Token(usize::MAX));
+        conn.proto = Proto::Resp2;
+        conn
+    }
+
+    /// A RESP2 connection with a socket, which the checks apply to as to
+    /// any client, and the other end of it.
+    fn client() -> (Conn, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = TcpStream::from_std(listener.accept().unwrap().0);
+        let mut conn = Conn::new(Some(Stream::Tcp(stream)), Token(usize::MAX - 1));
+        conn.proto = Proto::Resp2;
+        (conn, peer)
+    }
+
+    /// Runs `cmd` on `conn`, returning the reply.
+    fn request(conn: &mut Conn, cmd: &[&str]) -> Vec<u8> {
+        let cmd: Vec<Vec<u8>> = cmd.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        let mut out = Vec::new();
+        conn.do_request(&cmd, &mut out).unwrap();
+        out
+    }
+
+    /// Runs `cmd` on a connection of its own, returning the reply.
+    fn run(cmd: &[&str]) -> Vec<u8> {
+        request(&mut conn(), cmd)
+    }
+
+    #[test]
+    fn transaction_runs_at_exec() {
+        let _globals = globals();
+        let mut conn = conn();
+        assert_eq!(request(&mut conn, &["multi"]), b"+OK\r\n");
+        assert_eq!(
+            request(&mut conn, &["set", "test:multi", "1"]),
+            b"+QUEUED\r\n"
+        );
+        assert_eq!(request(&mut conn, &["incr", "test:multi"]), b"+QUEUED\r\n");
+        assert_eq!(request(&mut conn, &["get", "test:multi"]), b"+QUEUED\r\n");
+        assert_eq!(
+            request(&mut conn, &["exec"]),
+            b"*3\r\n+OK\r\n:2\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(
+            request(&mut conn, &["exec"]),
+            b"-ERR EXEC without MULTI\r\n"
+        );
+    }
+
+    #[test]
+    fn transaction_with_errors_is_discarded() {
+        let _globals = globals();
+        let mut conn = conn();
+        request(&mut conn, &["multi"]);
+        assert_eq!(
+            request(&mut conn, &["set", "test:abort", "1"]),
+            b"+QUEUED\r\n"
+        );
+        assert_eq!(
+            request(&mut conn, &["nosuchcommand"]),
+            b"-ERR Unknown CMD\r\n"
+        );
+        // a wrong number of arguments is caught when queued too
+        assert_eq!(request(&mut conn, &["get"]), b"-ERR Unknown CMD\r\n");
+        assert_eq!(
+            request(&mut conn, &["subscribe", "test:abort"]),
+            b"-ERR command not allowed inside a transaction\r\n"
+        );
+        assert_eq!(
+            request(&mut conn, &["exec"]),
+            b"-EXECABORT Transaction discarded because of previous errors.\r\n"
+        );
+        assert_eq!(request(&mut conn, &["get", "test:abort"]), b"$-1\r\n");
+    }
+
+    #[test]
+    fn replica_refuses_writes() {
+        let _globals = globals();
+        G_REPL.lock().unwrap().follow("127.0.0.1".to_string(), 1);
+        let mut conn = conn();
+        let set = request(&mut conn, &["set", "test:readonly", "1"]);
+        let get = request(&mut conn, &["get", "test:readonly"]);
+        request(&mut conn, &["multi"]);
+        let queued = request(&mut conn, &["del", "test:readonly"]);
+        let exec = request(&mut conn, &["exec"]);
+        G_REPL.lock().unwrap().become_primary();
+
+        let readonly = b"-READONLY You can't write against a read only replica.\r\n";
+        assert_eq!(set, readonly);
+        assert_eq!(get, b"$-1\r\n");
+        assert_eq!(queued, readonly);
+        assert!(exec.starts_with(b"-EXECABORT"));
+    }
+
+    #[test]
+    fn commands_denied_out_of_memory() {
+        let _globals = globals();
+        let (mut conn, _peer) = client();
+        let policy = {
+            let mut config = G_CONFIG.write().unwrap();
+            config.maxmemory = 1;
+            std::mem::replace(
+                &mut config.maxmemory_policy,
+                keyspace::EvictionPolicy::NoEviction,
+            )
+        };
+        let set = request(&mut conn, &["set", "test:oom", "1"]);
+        let get = request(&mut conn, &["get", "test:oom"]);
+        let del = request(&mut conn, &["del", "test:oom"]);
+        {
+            let mut config = G_CONFIG.write().unwrap();
+            config.maxmemory = 0;
+            config.maxmemory_policy = policy;
+        }
+
+        let oom = b"-OOM command not allowed when used memory > 'maxmemory'.\r\n";
+        assert_eq!(set, oom);
+        assert_eq!(get, b"$-1\r\n");
+        assert_eq!(del, b":0\r\n");
+    }
+
+    #[test]
+    fn users_are_checked() {
+        let _globals = globals();
+        let (mut conn, _peer) = client();
+        let rules = ["on", ">secret", "~test:cache:*", "+@read"];
+        G_ACL
+            .lock()
+            .unwrap()
+            .set_user("test:reader", &rules)
+            .unwrap();
+        conn.user = None;
+        let unauthenticated = request(&mut conn, &["get", "test:cache:1"]);
+        let wrongpass = request(&mut conn, &["auth", "test:reader", "guess"]);
+        let auth = request(&mut conn, &["auth", "test:reader", "secret"]);
+        let get = request(&mut conn, &["get", "test:cache:1"]);
+        let other_key = request(&mut conn, &["get", "test:other"]);
+        let set = request(&mut conn, &["set", "test:cache:1", "1"]);
+        G_ACL.lock().unwrap().del_user("test:reader");
+        // a removed user has to authenticate again
+        let removed = request(&mut conn, &["get", "test:cache:1"]);
+
+        assert_eq!(unauthenticated, b"-NOAUTH Authentication required.\r\n");
+        assert!(wrongpass.starts_with(b"-WRONGPASS"));
+        assert_eq!(auth, b"+OK\r\n");
+        assert_eq!(get, b"$-1\r\n");
+        assert_eq!(other_key, b"-NOPERM No permissions to access a key\r\n");
+        assert_eq!(
+            set,
+            b"-NOPERM User test:reader has no permissions to run the 'set' command\r\n"
+        );
+        assert_eq!(removed, b"-NOAUTH Authentication required.\r\n");
+    }
+
+    #[test]
+    fn subscribers_run_only_pubsub_commands() {
+        let _globals = globals();
+        let mut conn = conn();
+        request(&mut conn, &["subscribe", "test:channel"]);
+        assert_eq!(
+            request(&mut conn, &["get", "test:channel"]),
+            b"-ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context\r\n"
+        );
+        assert_eq!(
+            request(&mut conn, &["ping"]),
+            b"*2\r\n$4\r\npong\r\n$0\r\n\r\n"
+        );
+        conn.proto = Proto::Resp3;
+        assert_eq!(request(&mut conn, &["get", "test:channel"]), b"_\r\n");
+    }
+
+    #[test]
+    fn empty_requests_are_skipped() {
+        let _globals = globals();
+        let mut conn = Conn::new(None, Token(usize::MAX));
+        conn.rbuf = b"*0\r\n*1\r\n$4\r\nPING\r\n*0\r\n".to_vec();
+        while conn.try_one_request() {}
+        assert_eq!(conn.wbuf, b"+PONG\r\n");
+    }
+
+    #[test]
+    fn empty_command() {
+        let _globals = globals();
+        assert_eq!(run(&[]), b"-ERR Unknown CMD\r\n");
+    }
+
+    #[test]
+    fn blocking_pops_without_elements() {
+        let _globals = globals();
+        // without a socket nothing blocks, the commands answer as if they
+        // had timed out
+        assert_eq!(run(&["blpop", "test:blpop", "0"]), b"*-1\r\n");
+        assert_eq!(run(&["brpop", "test:blpop", "test:brpop", "0"]), b"*-1\r\n");
+        assert_eq!(
+            run(&["blmove", "test:blpop", "test:brpop", "left", "right", "0"]),
+            b"$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn srandmember_with_repeats() {
+        let _globals = globals();
+        run(&["sadd", "test:srandmember", "a", "b"]);
+        let reply = run(&["srandmember", "test:srandmember", "-5"]);
+        let (header, members) = reply.split_at(4);
+        assert_eq!(header, b"*5\r\n");
+        assert_eq!(members.len(), 5 * 7);
+        assert!(members
+            .chunks(7)
+            .all(|m| m == b"$1\r\na\r\n" || m == b"$1\r\nb\r\n"));
+        assert_eq!(
+            run(&["srandmember", "test:srandmember", "5"])[..4],
+            *b"*2\r\n"
+        );
+    }
+
+    #[test]
+    fn srandmember_huge_negative_count() {
+        let _globals = globals();
+        run(&["sadd", "test:srandmember:huge", "a"]);
+        for count in [
+            "-9223372036854775808",
+            "-9223372036854775807",
+            "-4294967296",
+        ] {
+            let reply = run(&["srandmember", "test:srandmember:huge", count]);
+            assert_eq!(reply, b"-ERR count too large for a reply\r\n");
+        }
     }
 }
@@ -0,0 +1,60 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static USED: AtomicUsize = AtomicUsize::new(0);
+// the part of it in the buffers of the connections
+static BUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, keeping count of the bytes in use. Installed as
+/// the global allocator, so the count covers the keys and values of the
+/// keyspace along with everything else the server allocates.
+pub struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            USED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc_zeroed(layout);
+        if !p.is_null() {
+            USED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        System.dealloc(p, layout);
+        USED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, p: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let q = System.realloc(p, layout, new_size);
+        if !q.is_null() {
+            USED.fetch_add(new_size, Ordering::Relaxed);
+            USED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        q
+    }
+}
+
+/// Returns the number of bytes allocated and not freed yet.
+pub fn used() -> usize {
+    USED.load(Ordering::Relaxed)
+}
+
+/// Records that the buffers of a connection went from `old` to `new`
+/// bytes.
+pub fn resize_buffers(old: usize, new: usize) {
+    BUFFERS.fetch_add(new, Ordering::Relaxed);
+    BUFFERS.fetch_sub(old, Ordering::Relaxed);
+}
+
+/// Returns the bytes held by the buffers of all connections.
+pub fn buffers() -> usize {
+    BUFFERS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,71 @@
+use crate::G_PUBSUB;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// event classes, selected with the letters of `parse`
+pub const KEYSPACE: u32 = 1 << 0; // K: publish on __keyspace@0__:<key>
+pub const KEYEVENT: u32 = 1 << 1; // E: publish on __keyevent@0__:<event>
+pub const GENERIC: u32 = 1 << 2; // g: del, expire, persist
+pub const STRING: u32 = 1 << 3; // $
+pub const LIST: u32 = 1 << 4; // l
+pub const SET: u32 = 1 << 5; // s
+pub const HASH: u32 = 1 << 6; // h
+pub const ZSET: u32 = 1 << 7; // z
+pub const EXPIRED: u32 = 1 << 8; // x
+pub const EVICTED: u32 = 1 << 9; // e
+pub const NEW: u32 = 1 << 10; // n
+                              // A: every class but `n`, like redis
+const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED;
+
+const LETTERS: [(char, u32); 11] = [
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('n', NEW),
+];
+
+static FLAGS: AtomicU32 = AtomicU32::new(0);
+
+/// Parses a redis `notify-keyspace-events` string such as `KEA` or `Elg`.
+/// Returns `None` on an unknown letter.
+pub fn parse(s: &str) -> Option<u32> {
+    s.chars().try_fold(0, |flags, c| {
+        if c == 'A' {
+            return Some(flags | ALL);
+        }
+        LETTERS
+            .iter()
+            .find(|(letter, _)| *letter == c)
+            .map(|(_, class)| flags | class)
+    })
+}
+
+/// Selects the event classes to publish. Nothing is published unless
+/// `KEYSPACE` or `KEYEVENT` is set along with some class.
+pub fn set_flags(flags: u32) {
+    FLAGS.store(flags, Ordering::Relaxed);
+}
+
+/// Publishes `event` for `key` if its class is enabled.
+pub fn notify(class: u32, event: &str, key: &[u8]) {
+    let flags = FLAGS.load(Ordering::Relaxed);
+    if flags & class == 0 {
+        return;
+    }
+    let mut pubsub = G_PUBSUB.lock().unwrap();
+    if flags & KEYSPACE != 0 {
+        let mut channel = b"__keyspace@0__:".to_vec();
+        channel.extend(key);
+        pubsub.publish(&channel, event.as_bytes());
+    }
+    if flags & KEYEVENT != 0 {
+        let channel = format!("__keyevent@0__:{event}");
+        pubsub.publish(channel.as_bytes(), key);
+    }
+}
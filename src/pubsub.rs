@@ -0,0 +1,120 @@
+use crate::glob;
+use mio::Token;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// A message on its way to one subscriber.
+pub struct Delivery {
+    pub token: Token,
+    // the pattern the subscriber matched the channel with, if any
+    pub pattern: Option<Vec<u8>>,
+    pub channel: Vec<u8>,
+    pub message: Arc<Vec<u8>>,
+}
+
+/// Who subscribed to what. Publishing only queues the messages, the event
+/// loop writes them to the subscribers since it owns the connections.
+pub struct PubSub {
+    channels: HashMap<Vec<u8>, HashSet<Token>>,
+    patterns: HashMap<Vec<u8>, HashSet<Token>>,
+    outbox: Vec<Delivery>,
+}
+
+/// Adds `token` to the subscribers of `name`, returns `false` if it was one.
+fn add(subs: &mut HashMap<Vec<u8>, HashSet<Token>>, name: &[u8], token: Token) -> bool {
+    subs.entry(name.to_vec()).or_default().insert(token)
+}
+
+/// Removes `token` from the subscribers of `name`, returns `false` if it
+/// was not one.
+fn remove(subs: &mut HashMap<Vec<u8>, HashSet<Token>>, name: &[u8], token: Token) -> bool {
+    let Some(tokens) = subs.get_mut(name) else {
+        return false;
+    };
+    let removed = tokens.remove(&token);
+    if tokens.is_empty() {
+        subs.remove(name);
+    }
+    removed
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, token: Token, channel: &[u8]) -> bool {
+        add(&mut self.channels, channel, token)
+    }
+
+    pub fn unsubscribe(&mut self, token: Token, channel: &[u8]) -> bool {
+        remove(&mut self.channels, channel, token)
+    }
+
+    pub fn psubscribe(&mut self, token: Token, pattern: &[u8]) -> bool {
+        add(&mut self.patterns, pattern, token)
+    }
+
+    pub fn punsubscribe(&mut self, token: Token, pattern: &[u8]) -> bool {
+        remove(&mut self.patterns, pattern, token)
+    }
+
+    /// Queues `message` for every subscriber of `channel`, and for every
+    /// matching pattern subscription. Returns how many will receive it.
+    pub fn publish(&mut self, channel: &[u8], message: &[u8]) -> usize {
+        let message = Arc::new(message.to_vec());
+        let before = self.outbox.len();
+        for &token in self.channels.get(channel).into_iter().flatten() {
+            self.outbox.push(Delivery {
+                token,
+                pattern: None,
+                channel: channel.to_vec(),
+                message: message.clone(),
+            });
+        }
+        for (pattern, tokens) in &self.patterns {
+            if !glob::matches(pattern, channel) {
+                continue;
+            }
+            for &token in tokens {
+                self.outbox.push(Delivery {
+                    token,
+                    pattern: Some(pattern.clone()),
+                    channel: channel.to_vec(),
+                    message: message.clone(),
+                });
+            }
+        }
+        self.outbox.len() - before
+    }
+
+    /// Takes the messages queued since the last call.
+    pub fn take_outbox(&mut self) -> Vec<Delivery> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Returns the channels with at least one subscriber, optionally only
+    /// those matching `pattern`.
+    pub fn active_channels(&self, pattern: Option<&[u8]>) -> Vec<&[u8]> {
+        self.channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|p| glob::matches(p, channel)))
+            .map(Vec::as_slice)
+            .collect()
+    }
+
+    /// Returns the number of subscribers of `channel`, not counting
+    /// pattern subscriptions.
+    pub fn numsub(&self, channel: &[u8]) -> usize {
+        self.channels.get(channel).map_or(0, HashSet::len)
+    }
+
+    /// Returns the number of distinct patterns subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+}
@@ -0,0 +1,159 @@
+use crate::set::rand_u64;
+use mio::Token;
+use std::collections::{HashSet, VecDeque};
+use std::time::Instant;
+
+/// The last writes sent to replicas, so that a replica that lost its link
+/// for a moment can catch up from where it was instead of copying the
+/// whole keyspace again.
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    cap: usize,
+    // the number of bytes ever fed, the offset of the end of `buf`
+    offset: u64,
+}
+
+impl Backlog {
+    pub fn new(cap: usize) -> Backlog {
+        Backlog {
+            buf: VecDeque::new(),
+            cap,
+            offset: 0,
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Appends `data`, dropping the oldest bytes over the capacity.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+        if self.buf.len() > self.cap {
+            self.buf.drain(..self.buf.len() - self.cap);
+        }
+        self.offset += data.len() as u64;
+    }
+
+    /// Returns the bytes the backlog takes.
+    pub fn memory(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Returns the bytes fed after `offset`, or `None` if they are not all
+    /// held anymore.
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let start = self.offset - self.buf.len() as u64;
+        if offset < start || offset > self.offset {
+            return None;
+        }
+        Some(
+            self.buf
+                .range((offset - start) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+/// Returns a random 40 character hex id naming a history of writes.
+fn new_replid() -> String {
+    (0..5)
+        .map(|_| format!("{:08x}", rand_u64() as u32))
+        .collect()
+}
+
+/// The replication state of the server: a primary with a backlog for its
+/// replicas, or a replica of the primary set with REPLICAOF.
+///
+/// The event loop owns the connections, so this only records what they
+/// should be; `replication_cron` in main connects and drops them.
+pub struct Replication {
+    // the id of the history of writes in `backlog`
+    pub replid: String,
+    // created once the first replica connects
+    backlog: Option<Backlog>,
+    // the connections of our replicas
+    pub replicas: HashSet<Token>,
+    // the primary we replicate, `None` if we are one
+    pub primary: Option<(String, u16)>,
+    // the connection to the primary, and the primary it goes to
+    pub link: Option<(Token, String, u16)>,
+    // whether the link got through to the first sync
+    pub link_synced: bool,
+    pub last_connect: Option<Instant>,
+    // where we are in the history of the primary, for a partial resync
+    pub primary_replid: String,
+    pub primary_offset: u64,
+}
+
+impl Replication {
+    pub fn new() -> Replication {
+        Replication {
+            replid: new_replid(),
+            backlog: None,
+            replicas: HashSet::new(),
+            primary: None,
+            link: None,
+            link_synced: false,
+            last_connect: None,
+            primary_replid: "?".to_string(),
+            primary_offset: 0,
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// Starts replicating `host:port`.
+    pub fn follow(&mut self, host: String, port: u16) {
+        if self.primary.as_ref() == Some(&(host.clone(), port)) {
+            return;
+        }
+        self.primary = Some((host, port));
+        // our own history ends here
+        self.backlog = None;
+        self.primary_replid = "?".to_string();
+        self.primary_offset = 0;
+        self.last_connect = None;
+    }
+
+    /// Stops replicating and starts a history of our own.
+    pub fn become_primary(&mut self) {
+        if self.primary.take().is_none() {
+            return;
+        }
+        self.replid = new_replid();
+        self.backlog = None;
+    }
+
+    /// Starts keeping the last `size` bytes of writes for replicas, if not
+    /// done yet.
+    pub fn start_backlog(&mut self, size: usize) {
+        if self.backlog.is_none() {
+            self.backlog = Some(Backlog::new(size));
+        }
+    }
+
+    /// Records a write for the replicas.
+    pub fn feed(&mut self, req: &[u8]) {
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.feed(req);
+        }
+    }
+
+    /// Returns the offset of the latest write in our history.
+    pub fn offset(&self) -> u64 {
+        self.backlog.as_ref().map_or(0, Backlog::offset)
+    }
+
+    pub fn backlog_memory(&self) -> usize {
+        self.backlog.as_ref().map_or(0, Backlog::memory)
+    }
+
+    /// Returns the writes after `offset`, see `Backlog::since`.
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        self.backlog.as_ref()?.since(offset)
+    }
+}
@@ -0,0 +1,281 @@
+use std::io::{self, Error, ErrorKind};
+
+// the longest `*<n>` or `$<n>` header line we accept
+const K_MAX_LINE: usize = 32;
+
+/// Decides from the first bytes of a connection whether the client speaks
+/// RESP, i.e. opens with an array header `*<digits>\r\n`. Returns `None`
+/// while there isn't enough data to tell.
+///
+/// A request in the binary framing could only look like this if its length
+/// prefix happened to spell out such a line, which takes a 168 MB request.
+pub fn detect(data: &[u8]) -> Option<bool> {
+    match data.first() {
+        None => return None,
+        Some(&b'*') => {}
+        Some(_) => return Some(false),
+    }
+    let digits = data[1..].iter().take_while(|b| b.is_ascii_digit()).count();
+    match &data[1 + digits..] {
+        [] => None,
+        [b'\r'] if digits > 0 => None,
+        [b'\r', b'\n', ..] if digits > 0 => Some(true),
+        _ => Some(false),
+    }
+}
+
+fn bad(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Protocol error: {msg}"))
+}
+
+/// Parses a `<prefix><int>\r\n` line at `pos`, returning the integer and the
+/// position after the line, or `None` if the line is incomplete.
+fn parse_header(data: &[u8], pos: usize, prefix: u8) -> io::Result<Option<(i64, usize)>> {
+    let line = &data[pos..];
+    let end = match line.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if line.len() > K_MAX_LINE => return Err(bad("header line too long")),
+        None => return Ok(None),
+    };
+    if line[0] != prefix {
+        return Err(bad(&format!("expected '{}'", prefix as char)));
+    }
+    std::str::from_utf8(&line[1..end])
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .map(|n| Some((n, pos + end + 2)))
+        .ok_or_else(|| bad("invalid length"))
+}
+
+/// Parses one request, an array of bulk strings, from the start of `data`.
+/// Returns the arguments and the number of bytes consumed, or `None` if
+/// the request is not complete yet. Empty arrays are skipped, as redis
+/// does, so a request always has a command.
+pub fn parse_request(
+    data: &[u8],
+    max_args: usize,
+    max_len: usize,
+) -> io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let mut pos = 0;
+    let n = loop {
+        match parse_header(data, pos, b'*')? {
+            Some((0, next)) => pos = next,
+            Some((n, next)) => {
+                pos = next;
+                break n;
+            }
+            None => return Ok(None),
+        }
+    };
+    if n < 0 || n as usize > max_args {
+        return Err(bad("invalid multibulk length"));
+    }
+    let mut cmd = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let (len, start) = match parse_header(data, pos, b'$')? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if len < 0 || len as usize > max_len {
+            return Err(bad("invalid bulk length"));
+        }
+        let end = start + len as usize;
+        if data.len() < end + 2 {
+            return Ok(None);
+        }
+        if &data[end..end + 2] != b"\r\n" {
+            return Err(bad("expected CRLF after bulk string"));
+        }
+        cmd.push(data[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((cmd, pos)))
+}
+
+pub fn put_simple(out: &mut Vec<u8>, s: &str) {
+    out.push(b'+');
+    out.extend(s.as_bytes());
+    out.extend(b"\r\n");
+}
+
+/// Writes an error, `code` is the leading word such as `ERR` or `WRONGTYPE`.
+pub fn put_error(out: &mut Vec<u8>, code: &str, msg: &str) {
+    out.push(b'-');
+    out.extend(code.as_bytes());
+    out.push(b' ');
+    // an error is a single line
+    out.extend(msg.replace(['\r', '\n'], " ").as_bytes());
+    out.extend(b"\r\n");
+}
+
+pub fn put_int(out: &mut Vec<u8>, n: i64) {
+    out.extend(format!(":{n}\r\n").as_bytes());
+}
+
+pub fn put_bulk(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend(format!("${}\r\n", s.len()).as_bytes());
+    out.extend(s);
+    out.extend(b"\r\n");
+}
+
+pub fn put_null(out: &mut Vec<u8>, resp3: bool) {
+    out.extend(if resp3 {
+        &b"_\r\n"[..]
+    } else {
+        &b"$-1\r\n"[..]
+    });
+}
+
+/// Writes the null of an array reply, which RESP2 tells apart from a null
+/// string.
+pub fn put_null_array(out: &mut Vec<u8>, resp3: bool) {
+    out.extend(if resp3 {
+        &b"_\r\n"[..]
+    } else {
+        &b"*-1\r\n"[..]
+    });
+}
+
+pub fn put_array(out: &mut Vec<u8>, len: usize) {
+    out.extend(format!("*{len}\r\n").as_bytes());
+}
+
+/// Writes the header of a push, out-of-band data such as pub/sub messages,
+/// which RESP2 sends as a plain array.
+pub fn put_push(out: &mut Vec<u8>, len: usize, resp3: bool) {
+    if resp3 {
+        out.extend(format!(">{len}\r\n").as_bytes());
+    } else {
+        put_array(out, len);
+    }
+}
+
+/// Writes a map header for `len` pairs, a flat array of twice the length
+/// in RESP2.
+pub fn put_map(out: &mut Vec<u8>, len: usize, resp3: bool) {
+    if resp3 {
+        out.extend(format!("%{len}\r\n").as_bytes());
+    } else {
+        put_array(out, len * 2);
+    }
+}
+
+/// Writes a double, which RESP2 sends as a bulk string.
+pub fn put_double(out: &mut Vec<u8>, d: f64, resp3: bool) {
+    let s = fmt_double(d);
+    if resp3 {
+        out.extend(format!(",{s}\r\n").as_bytes());
+    } else {
+        put_bulk(out, s.as_bytes());
+    }
+}
+
+/// Formats a double the way redis does: `inf`, `-inf`, or the shortest
+/// representation that round-trips, in scientific notation like `%.17g`
+/// once the exponent is below -4 or above 16, e.g. `1e+300`.
+pub fn fmt_double(d: f64) -> String {
+    if d.is_infinite() {
+        return if d > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let sci = format!("{d:e}");
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if (-4..17).contains(&exp) {
+        format!("{d}")
+    } else {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{mantissa}e{sign}{:02}", exp.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+        parse_request(data, 16, 64)
+    }
+
+    #[test]
+    fn detect_protocol() {
+        assert_eq!(detect(b""), None);
+        assert_eq!(detect(b"*"), None);
+        assert_eq!(detect(b"*12"), None);
+        assert_eq!(detect(b"*1\r"), None);
+        assert_eq!(detect(b"*1\r\n"), Some(true));
+        assert_eq!(detect(b"*\r\n"), Some(false));
+        assert_eq!(detect(b"*1x"), Some(false));
+        assert_eq!(detect(b"\x0c\x00\x00\x00"), Some(false));
+    }
+
+    #[test]
+    fn parse_whole_requests() {
+        let req = b"*2\r\n$3\r\nGET\r\n$0\r\n\r\n*1\r\n$4\r\nPING\r\n";
+        let (cmd, used) = parse(req).unwrap().unwrap();
+        assert_eq!(cmd, [b"GET".to_vec(), Vec::new()]);
+        let (cmd, rest) = parse(&req[used..]).unwrap().unwrap();
+        assert_eq!(cmd, [b"PING".to_vec()]);
+        assert_eq!(used + rest, req.len());
+
+        // bulk strings are binary safe
+        let (cmd, _) = parse(b"*1\r\n$4\r\na\r\nb\r\n").unwrap().unwrap();
+        assert_eq!(cmd, [b"a\r\nb".to_vec()]);
+
+        // empty arrays are skipped
+        assert_eq!(parse(b"*0\r\n").unwrap(), None);
+        let req = b"*0\r\n*0\r\n*1\r\n$4\r\nPING\r\n";
+        assert_eq!(
+            parse(req).unwrap(),
+            Some((vec![b"PING".to_vec()], req.len()))
+        );
+    }
+
+    #[test]
+    fn parse_partial_frames() {
+        let req = b"*2\r\n$3\r\nSET\r\n$5\r\nhello\r\n";
+        for end in 0..req.len() {
+            assert_eq!(parse(&req[..end]).unwrap(), None, "cut at {end}");
+        }
+        assert!(parse(req).unwrap().is_some());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |data: &[u8]| parse(data).unwrap_err().to_string();
+        assert_eq!(err(b"*17\r\n"), "Protocol error: invalid multibulk length");
+        assert_eq!(err(b"*-1\r\n"), "Protocol error: invalid multibulk length");
+        assert_eq!(err(b"*1\r\n$65\r\n"), "Protocol error: invalid bulk length");
+        assert_eq!(err(b"*1\r\n+OK\r\n"), "Protocol error: expected '$'");
+        assert_eq!(err(b"*x\r\n"), "Protocol error: invalid length");
+        assert_eq!(
+            err(b"*1\r\n$1\r\nab\r\n"),
+            "Protocol error: expected CRLF after bulk string"
+        );
+        assert_eq!(err(&[b'*'; 40]), "Protocol error: header line too long");
+    }
+
+    #[test]
+    fn format_doubles() {
+        assert_eq!(fmt_double(0.0), "0");
+        assert_eq!(fmt_double(1.5), "1.5");
+        assert_eq!(fmt_double(-3.0), "-3");
+        assert_eq!(fmt_double(0.1), "0.1");
+        assert_eq!(fmt_double(0.0001), "0.0001");
+        assert_eq!(fmt_double(0.00001), "1e-05");
+        assert_eq!(fmt_double(1e16), "10000000000000000");
+        assert_eq!(fmt_double(1e17), "1e+17");
+        assert_eq!(fmt_double(1e300), "1e+300");
+        assert_eq!(fmt_double(-1.25e-300), "-1.25e-300");
+        assert_eq!(fmt_double(f64::MAX), "1.7976931348623157e+308");
+        assert_eq!(fmt_double(f64::INFINITY), "inf");
+        assert_eq!(fmt_double(f64::NEG_INFINITY), "-inf");
+        for d in [1e300, 1.7976931348623157e308, 5e-324, 123.456e-20] {
+            assert_eq!(fmt_double(d).parse::<f64>().unwrap(), d);
+        }
+
+        let mut out = Vec::new();
+        put_double(&mut out, 1e300, false);
+        put_double(&mut out, 2.5, true);
+        assert_eq!(out, b"$6\r\n1e+300\r\n,2.5\r\n");
+    }
+}
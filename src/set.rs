@@ -0,0 +1,201 @@
+use crate::hashtable::HMap;
+use crate::keyspace::now_ms;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// the most members kept in the integer encoding
+const K_INTSET_MAX: usize = 512;
+
+/// A set of byte strings. While every member is a small integer the set is
+/// kept as a sorted vector of them, and converted to a hash map once a
+/// member that is not (or the 513th member) is added.
+pub enum Set {
+    Ints(Vec<i64>),
+    Hash(HMap<Vec<u8>, ()>),
+}
+
+/// Parses a member that the integer encoding can hold, i.e. one that
+/// formats back to exactly the same bytes.
+fn as_int(member: &[u8]) -> Option<i64> {
+    let n = std::str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+    (n.to_string().as_bytes() == member).then_some(n)
+}
+
+/// Returns a pseudo-random number, from a xorshift generator seeded with
+/// the clock.
+pub fn rand_u64() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let mut x = STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = now_ms() | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x, Ordering::Relaxed);
+    x
+}
+
+impl Set {
+    pub fn new() -> Set {
+        Set::Ints(Vec::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Ints(ints) => ints.len(),
+            Set::Hash(hash) => hash.hm_size(),
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => as_int(member).is_some_and(|n| ints.binary_search(&n).is_ok()),
+            Set::Hash(hash) => hash.hm_lookup(member).is_some(),
+        }
+    }
+
+    /// Adds `member`, returns `true` if it is new.
+    pub fn add(&mut self, member: &[u8]) -> bool {
+        if let Set::Ints(ints) = self {
+            match as_int(member) {
+                Some(n) => match ints.binary_search(&n) {
+                    Ok(_) => return false,
+                    Err(i) if ints.len() < K_INTSET_MAX => {
+                        ints.insert(i, n);
+                        return true;
+                    }
+                    Err(_) => self.convert(),
+                },
+                None => self.convert(),
+            }
+        }
+        match self {
+            Set::Hash(hash) => hash.hm_insert(member.to_vec(), ()).is_none(),
+            Set::Ints(_) => unreachable!("converted above"),
+        }
+    }
+
+    /// Switches to the hash map encoding.
+    fn convert(&mut self) {
+        let mut hash = HMap::new();
+        for member in self.members() {
+            hash.hm_insert(member, ());
+        }
+        *self = Set::Hash(hash);
+    }
+
+    /// Removes `member`, returns `true` if it was in the set.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => match as_int(member).map(|n| ints.binary_search(&n)) {
+                Some(Ok(i)) => {
+                    ints.remove(i);
+                    true
+                }
+                _ => false,
+            },
+            Set::Hash(hash) => hash.hm_delete(member).is_some(),
+        }
+    }
+
+    /// Returns all members, in ascending order for the integer encoding.
+    pub fn members(&self) -> Vec<Vec<u8>> {
+        match self {
+            Set::Ints(ints) => ints.iter().map(|n| n.to_string().into_bytes()).collect(),
+            Set::Hash(hash) => hash.iter().map(|(member, _)| member.clone()).collect(),
+        }
+    }
+
+    /// Returns a random member.
+    pub fn random(&self) -> Option<Vec<u8>> {
+        match self {
+            Set::Ints(ints) if ints.is_empty() => None,
+            Set::Ints(ints) => Some(
+                ints[rand_u64() as usize % ints.len()]
+                    .to_string()
+                    .into_bytes(),
+            ),
+            Set::Hash(hash) => hash.hm_random(rand_u64()).map(|(member, _)| member.clone()),
+        }
+    }
+
+    /// Visits some of the members starting at `cursor`, like
+    /// `HMap::hm_scan`. The integer encoding is small and visited whole.
+    pub fn scan<F: FnMut(&[u8])>(&self, cursor: u64, mut f: F) -> u64 {
+        match self {
+            Set::Ints(ints) => {
+                for n in ints {
+                    f(n.to_string().as_bytes());
+                }
+                0
+            }
+            Set::Hash(hash) => hash.hm_scan(cursor, |member, _| f(member)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut members: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn integer_encoding() {
+        let mut set = Set::new();
+        assert!(set.add(b"3"));
+        assert!(set.add(b"-1"));
+        assert!(!set.add(b"3"));
+        assert!(matches!(set, Set::Ints(_)));
+        assert_eq!(set.members(), [b"-1".to_vec(), b"3".to_vec()]);
+        // not the canonical form of an integer, so not one for the set
+        assert!(set.add(b"03"));
+        assert!(matches!(set, Set::Hash(_)));
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(b"3") && set.contains(b"03") && !set.contains(b"+3"));
+    }
+
+    #[test]
+    fn converts_past_the_intset_limit() {
+        let mut set = Set::new();
+        for n in 0..K_INTSET_MAX {
+            set.add(n.to_string().as_bytes());
+        }
+        assert!(matches!(set, Set::Ints(_)));
+        set.add(b"100000");
+        assert!(matches!(set, Set::Hash(_)));
+        assert_eq!(set.len(), K_INTSET_MAX + 1);
+        assert!(set.contains(b"511") && set.contains(b"100000"));
+    }
+
+    #[test]
+    fn remove_random_scan() {
+        for first in [&b"1"[..], b"x"] {
+            let mut set = Set::new();
+            for member in [first, b"2", b"3"] {
+                set.add(member);
+            }
+            assert!(set.remove(b"2"));
+            assert!(!set.remove(b"2"));
+            for _ in 0..20 {
+                let member = set.random().unwrap();
+                assert!(member == first || member == b"3");
+            }
+            let mut seen = Vec::new();
+            let mut cursor = 0;
+            loop {
+                cursor = set.scan(cursor, |member| seen.push(member.to_vec()));
+                if cursor == 0 {
+                    break;
+                }
+            }
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen, sorted(set.members()));
+        }
+        assert_eq!(Set::new().random(), None);
+    }
+}
@@ -0,0 +1,434 @@
+use crate::hashtable::HMap;
+use crate::keyspace::{now_ms, Keyspace};
+use crate::list::List;
+use crate::log::log;
+use crate::set::Set;
+use crate::zset::ZSet;
+use crate::Value;
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+
+// Snapshot file layout, all integers little-endian:
+//
+//   magic | version u32 | aof offset u64
+//   (type u8 | deadline u64 | key | value)*
+//   T_EOF | crc32 u32 of everything before it
+//
+// Strings are `len u32 | bytes`. A deadline of 0 means the key never
+// expires. A sorted set value is `count u32 | (score f64 | name)*`, a hash
+// is `count u32 | (field | value)*`, and lists and sets are
+// `count u32 | value*`.
+const MAGIC: &[u8; 8] = b"RUSTISDB";
+const VERSION: u32 = 1;
+
+// type tags, one per kind of `Value`
+const T_STR: u8 = 0;
+const T_ZSET: u8 = 1;
+const T_HASH: u8 = 2;
+const T_LIST: u8 = 3;
+const T_SET: u8 = 4;
+const T_EOF: u8 = 0xff;
+
+/// Set while a BGSAVE is writing, only one may run at a time.
+pub static BGSAVE_RUNNING: AtomicBool = AtomicBool::new(false);
+/// Unix time in milliseconds of the last successful save.
+pub static LAST_SAVE_MS: AtomicU64 = AtomicU64::new(0);
+/// Whether the last save succeeded.
+pub static LAST_SAVE_OK: AtomicBool = AtomicBool::new(true);
+// the pid of the child process writing the snapshot of a BGSAVE, 0 for none
+static BGSAVE_CHILD: AtomicI32 = AtomicI32::new(0);
+
+// a BGSAVE forks like in redis: the child serializes the keyspace from its
+// copy-on-write view of the memory while the server carries on
+extern "C" {
+    fn fork() -> i32;
+    fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+    fn _exit(status: i32) -> !;
+}
+const WNOHANG: i32 = 1;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 (IEEE) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend((s.len() as u32).to_le_bytes());
+    buf.extend(s);
+}
+
+/// Serializes the keyspace. `aof_offset` is the size of the append-only
+/// file at this point, the log tail past it is replayed on top at load.
+pub fn encode(map: &Keyspace, aof_offset: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend(MAGIC);
+    buf.extend(VERSION.to_le_bytes());
+    buf.extend(aof_offset.to_le_bytes());
+    for (key, val, deadline) in map.iter() {
+        let tag = match val {
+            Value::Str(_) => T_STR,
+            Value::ZSet(_) => T_ZSET,
+            Value::Hash(_) => T_HASH,
+            Value::List(_) => T_LIST,
+            Value::Set(_) => T_SET,
+        };
+        buf.push(tag);
+        buf.extend(deadline.unwrap_or(0).to_le_bytes());
+        put_str(&mut buf, key);
+        match val {
+            Value::Str(s) => put_str(&mut buf, s),
+            Value::ZSet(zset) => {
+                buf.extend((zset.len() as u32).to_le_bytes());
+                for (name, score) in zset.iter_from(0) {
+                    buf.extend(score.to_le_bytes());
+                    put_str(&mut buf, name);
+                }
+            }
+            Value::Hash(hash) => {
+                buf.extend((hash.hm_size() as u32).to_le_bytes());
+                for (field, val) in hash.iter() {
+                    put_str(&mut buf, field);
+                    put_str(&mut buf, val);
+                }
+            }
+            Value::List(list) => {
+                buf.extend((list.len() as u32).to_le_bytes());
+                for val in list.iter_from(0) {
+                    put_str(&mut buf, val);
+                }
+            }
+            Value::Set(set) => {
+                buf.extend((set.len() as u32).to_le_bytes());
+                for member in set.members() {
+                    put_str(&mut buf, &member);
+                }
+            }
+        }
+    }
+    buf.push(T_EOF);
+    let crc = crc32(&buf);
+    buf.extend(crc.to_le_bytes());
+    buf
+}
+
+/// Writes a snapshot to a temporary file and renames it over `path`, so a
+/// crash never leaves a half written snapshot behind.
+pub fn write(path: &str, data: &[u8]) -> io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    let result = File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    LAST_SAVE_OK.store(result.is_ok(), Ordering::Release);
+    match result {
+        Ok(()) => LAST_SAVE_MS.store(now_ms(), Ordering::Release),
+        Err(_) => {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+    result
+}
+
+/// Writes a snapshot of `map` from a forked child process, which sees the
+/// keyspace as it was at the fork whatever happens to it meanwhile. Returns
+/// `Ok(false)` if another background save is still running.
+pub fn write_in_background(path: &str, map: &Keyspace, aof_offset: u64) -> io::Result<bool> {
+    if BGSAVE_RUNNING.swap(true, Ordering::AcqRel) {
+        return Ok(false);
+    }
+    // SAFETY: the child only encodes and writes the snapshot then exits
+    // without unwinding or running destructors
+    match unsafe { fork() } {
+        -1 => {
+            BGSAVE_RUNNING.store(false, Ordering::Release);
+            Err(Error::last_os_error())
+        }
+        0 => {
+            let ok = write(path, &encode(map, aof_offset)).is_ok();
+            unsafe { _exit(if ok { 0 } else { 1 }) }
+        }
+        pid => {
+            BGSAVE_CHILD.store(pid, Ordering::Release);
+            Ok(true)
+        }
+    }
+}
+
+/// Checks whether the child of a background save has exited and records
+/// how it went. Called from the event loop, it never waits.
+pub fn reap_background_save() {
+    let pid = BGSAVE_CHILD.load(Ordering::Acquire);
+    if pid == 0 {
+        return;
+    }
+    let mut status = 0;
+    // SAFETY: `status` outlives the call
+    let ok = match unsafe { waitpid(pid, &mut status, WNOHANG) } {
+        0 => return,
+        -1 => {
+            log!(
+                Warning,
+                "background saving lost its child: {}",
+                Error::last_os_error()
+            );
+            false
+        }
+        // exited normally with status 0
+        _ => status == 0,
+    };
+    if ok {
+        log!(Notice, "background saving finished");
+        LAST_SAVE_MS.store(now_ms(), Ordering::Release);
+    } else {
+        log!(Warning, "background saving failed");
+    }
+    LAST_SAVE_OK.store(ok, Ordering::Release);
+    BGSAVE_CHILD.store(0, Ordering::Release);
+    BGSAVE_RUNNING.store(false, Ordering::Release);
+}
+
+/// A cursor over snapshot data.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "snapshot cut short"));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// Loads the snapshot at `path` into `map`, skipping keys that have expired
+/// in the meantime. Returns the append-only file offset recorded in it, or
+/// `None` if there is no snapshot.
+pub fn load(path: &str, map: &mut Keyspace) -> io::Result<Option<u64>> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    decode(&data, map)
+        .map(Some)
+        .map_err(|e| Error::new(e.kind(), format!("{path}: {e}")))
+}
+
+/// Loads a snapshot from memory into `map`, like `load`. Returns the
+/// append-only file offset recorded in it.
+pub fn decode(data: &[u8], map: &mut Keyspace) -> io::Result<u64> {
+    let bad = |msg: &str| Error::new(ErrorKind::InvalidData, msg);
+    if data.len() < MAGIC.len() + 4 + 8 + 1 + 4 || !data.starts_with(MAGIC) {
+        return Err(bad("not a snapshot file"));
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(bad("checksum mismatch"));
+    }
+
+    let mut r = Reader {
+        data: body,
+        pos: MAGIC.len(),
+    };
+    if r.u32()? != VERSION {
+        return Err(bad("unsupported version"));
+    }
+    let aof_offset = r.u64()?;
+    let now = now_ms();
+    loop {
+        let tag = r.u8()?;
+        if tag == T_EOF {
+            break;
+        }
+        let deadline = r.u64()?;
+        let key = r.string()?;
+        let val = match tag {
+            T_STR => Value::Str(r.string()?),
+            T_ZSET => {
+                let mut zset = ZSet::new();
+                for _ in 0..r.u32()? {
+                    let score = r.f64()?;
+                    if score.is_nan() {
+                        return Err(bad("NaN score"));
+                    }
+                    zset.add(&r.string()?, score);
+                }
+                Value::ZSet(zset)
+            }
+            T_HASH => {
+                let mut hash = HMap::new();
+                for _ in 0..r.u32()? {
+                    hash.hm_insert(r.string()?, r.string()?);
+                }
+                Value::Hash(hash)
+            }
+            T_LIST => {
+                let mut list = List::new();
+                for _ in 0..r.u32()? {
+                    list.push_back(r.string()?);
+                }
+                Value::List(list)
+            }
+            T_SET => {
+                let mut set = Set::new();
+                for _ in 0..r.u32()? {
+                    set.add(&r.string()?);
+                }
+                Value::Set(set)
+            }
+            _ => return Err(bad("unknown type tag")),
+        };
+        if deadline != 0 && deadline <= now {
+            continue;
+        }
+        map.insert(&key, val);
+        if deadline != 0 {
+            map.expire_at(&key, deadline);
+        }
+    }
+    if r.pos != body.len() {
+        return Err(bad("trailing data"));
+    }
+    Ok(aof_offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Keyspace {
+        let mut map = Keyspace::new();
+        map.insert(b"str", Value::Str(b"\x00bytes\xff".to_vec()));
+        let mut zset = ZSet::new();
+        zset.add(b"a", 1.5);
+        zset.add(b"b", f64::NEG_INFINITY);
+        map.insert(b"zset", Value::ZSet(zset));
+        let mut hash = HMap::new();
+        hash.hm_insert(b"field".to_vec(), b"value".to_vec());
+        map.insert(b"hash", Value::Hash(hash));
+        let mut list = List::new();
+        for val in [b"x", b"y", b"z"] {
+            list.push_back(val.to_vec());
+        }
+        map.insert(b"list", Value::List(list));
+        let mut set = Set::new();
+        set.add(b"7");
+        set.add(b"member");
+        map.insert(b"set", Value::Set(set));
+        map.insert(b"volatile", Value::Str(b"v".to_vec()));
+        map.expire_at(b"volatile", now_ms() + 60_000);
+        map
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = encode(&sample(), 42);
+        let mut map = Keyspace::new();
+        assert_eq!(decode(&data, &mut map).unwrap(), 42);
+        assert_eq!(map.len(), 6);
+        assert!(matches!(map.get(b"str"), Some(Value::Str(s)) if s == b"\x00bytes\xff"));
+        let Some(Value::ZSet(zset)) = map.get(b"zset") else {
+            panic!("zset lost");
+        };
+        let items: Vec<_> = zset.iter_from(0).collect();
+        assert_eq!(items, [(&b"b"[..], f64::NEG_INFINITY), (&b"a"[..], 1.5)]);
+        let Some(Value::Hash(hash)) = map.get(b"hash") else {
+            panic!("hash lost");
+        };
+        assert_eq!(hash.hm_lookup(&b"field"[..]), Some(&b"value".to_vec()));
+        let Some(Value::List(list)) = map.get(b"list") else {
+            panic!("list lost");
+        };
+        assert_eq!(list.iter_from(0).collect::<Vec<_>>(), [b"x", b"y", b"z"]);
+        let Some(Value::Set(set)) = map.get(b"set") else {
+            panic!("set lost");
+        };
+        assert!(set.contains(b"7") && set.contains(b"member") && set.len() == 2);
+        assert!(map.deadline(b"volatile").is_some());
+        assert_eq!(map.deadline(b"str"), None);
+    }
+
+    #[test]
+    fn expired_keys_are_skipped() {
+        let mut map = Keyspace::new();
+        map.insert(b"gone", Value::Str(b"v".to_vec()));
+        map.expire_at(b"gone", now_ms() + 50);
+        let data = encode(&map, 0);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut loaded = Keyspace::new();
+        decode(&data, &mut loaded).unwrap();
+        assert_eq!(loaded.len(), 0);
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let data = encode(&sample(), 0);
+        let mut map = Keyspace::new();
+        let mut flipped = data.clone();
+        flipped[data.len() / 2] ^= 1;
+        let err = decode(&flipped, &mut map).unwrap_err();
+        assert_eq!(err.to_string(), "checksum mismatch");
+        let err = decode(&data[..data.len() - 1], &mut map).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(decode(b"not a snapshot at all", &mut map).is_err());
+    }
+
+    #[test]
+    fn crc32_known_answer() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::avl::{self, AvlTree};
+use crate::hashtable::HMap;
+use std::cmp::Ordering;
+
+/// The ordering key of a sorted set member: by score first, then by name.
+struct ZKey {
+    score: f64,
+    name: Vec<u8>,
+}
+
+impl Ord for ZKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // scores are never NaN, see `ZSet::add`
+        self.score
+            .partial_cmp(&other.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.name.cmp(&other.name))
+    }
+}
+
+impl PartialOrd for ZKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ZKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ZKey {}
+
+/// A sorted set. Members are indexed by name for score lookups and by
+/// (score, name) in an AVL tree for rank and range queries.
+pub struct ZSet {
+    tree: AvlTree<ZKey>,
+    index: HMap<Vec<u8>, f64>,
+}
+
+impl ZSet {
+    pub fn new() -> ZSet {
+        ZSet {
+            tree: AvlTree::new(),
+            index: HMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Adds `name` with `score`, or updates the score of an existing member.
+    /// Returns `true` if the member is new.
+    pub fn add(&mut self, name: &[u8], score: f64) -> bool {
+        assert!(!score.is_nan());
+        let old = self.index.hm_insert(name.to_vec(), score);
+        if let Some(old) = old {
+            if old == score {
+                return false;
+            }
+            self.tree.remove(&ZKey {
+                score: old,
+                name: name.to_vec(),
+            });
+        }
+        self.tree.insert(ZKey {
+            score,
+            name: name.to_vec(),
+        });
+        old.is_none()
+    }
+
+    /// Removes `name`, returns `true` if it was a member.
+    pub fn remove(&mut self, name: &[u8]) -> bool {
+        match self.index.hm_delete(name) {
+            None => false,
+            Some((_, score)) => {
+                self.tree.remove(&ZKey {
+                    score,
+                    name: name.to_vec(),
+                });
+                true
+            }
+        }
+    }
+
+    pub fn score(&self, name: &[u8]) -> Option<f64> {
+        self.index.hm_lookup(name).copied()
+    }
+
+    /// Returns the zero-based rank of `name` ordered by ascending score.
+    pub fn rank(&self, name: &[u8]) -> Option<usize> {
+        let score = self.score(name)?;
+        self.tree.rank(&ZKey {
+            score,
+            name: name.to_vec(),
+        })
+    }
+
+    /// Returns the rank of the first member whose score is greater than (or
+    /// equal to, unless `exclusive`) `min`.
+    pub fn seek(&self, min: f64, exclusive: bool) -> usize {
+        self.tree.partition_point(|k| {
+            if exclusive {
+                k.score <= min
+            } else {
+                k.score < min
+            }
+        })
+    }
+
+    /// Iterates over (name, score) pairs in order, starting at `rank`.
+    pub fn iter_from(&self, rank: usize) -> ZIter<'_> {
+        ZIter {
+            inner: self.tree.iter_from(rank),
+        }
+    }
+}
+
+pub struct ZIter<'a> {
+    inner: avl::Iter<'a, ZKey>,
+}
+
+impl<'a> Iterator for ZIter<'a> {
+    type Item = (&'a [u8], f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|k| (k.name.as_slice(), k.score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(zset: &ZSet, rank: usize) -> Vec<(String, f64)> {
+        zset.iter_from(rank)
+            .map(|(name, score)| (String::from_utf8_lossy(name).into_owned(), score))
+            .collect()
+    }
+
+    #[test]
+    fn add_update_remove() {
+        let mut zset = ZSet::new();
+        assert!(zset.add(b"a", 1.0));
+        assert!(zset.add(b"b", 2.0));
+        assert!(!zset.add(b"a", 3.0));
+        assert!(!zset.add(b"b", 2.0));
+        assert_eq!(zset.len(), 2);
+        assert_eq!(zset.score(b"a"), Some(3.0));
+        assert_eq!(range(&zset, 0), [("b".into(), 2.0), ("a".into(), 3.0)]);
+
+        assert!(zset.remove(b"b"));
+        assert!(!zset.remove(b"b"));
+        assert_eq!(zset.score(b"b"), None);
+        assert_eq!(range(&zset, 0), [("a".into(), 3.0)]);
+    }
+
+    #[test]
+    fn equal_scores_order_by_name() {
+        let mut zset = ZSet::new();
+        for name in [b"c", b"a", b"b"] {
+            zset.add(name, 1.0);
+        }
+        zset.add(b"z", 0.5);
+        assert_eq!(zset.rank(b"z"), Some(0));
+        assert_eq!(zset.rank(b"a"), Some(1));
+        assert_eq!(zset.rank(b"c"), Some(3));
+        assert_eq!(zset.rank(b"x"), None);
+        assert_eq!(range(&zset, 2), [("b".into(), 1.0), ("c".into(), 1.0)]);
+    }
+
+    #[test]
+    fn seek_by_score() {
+        let mut zset = ZSet::new();
+        for (i, name) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+            zset.add(*name, i as f64);
+        }
+        zset.add(b"e", f64::INFINITY);
+        assert_eq!(zset.seek(1.0, false), 1);
+        assert_eq!(zset.seek(1.0, true), 2);
+        assert_eq!(zset.seek(1.5, false), 2);
+        assert_eq!(zset.seek(f64::NEG_INFINITY, false), 0);
+        assert_eq!(zset.seek(f64::INFINITY, false), 4);
+        assert_eq!(zset.seek(f64::INFINITY, true), 5);
+        // a range with a LIMIT offset starts that many ranks further
+        let offset = 1;
+        let limited: Vec<_> = zset
+            .iter_from(zset.seek(1.0, false) + offset)
+            .take(2)
+            .collect();
+        assert_eq!(limited, [(&b"c"[..], 2.0), (&b"d"[..], 3.0)]);
+    }
+}